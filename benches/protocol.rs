@@ -0,0 +1,87 @@
+use chickens::{
+    aggregate_server_key_shares, derive_server_key, evaluate_circuit, gen_client_key,
+    gen_server_key_share, get_user_cell, setup, with_thread_pool, CircuitOutput, ClientKey,
+    Direction, GameStateEnc, ServerKeyShare, UserAction,
+};
+use criterion::{criterion_group, criterion_main, Criterion};
+use itertools::Itertools;
+use rocket::serde::msgpack;
+
+const TOTAL_USERS: usize = 4;
+
+/// Keys of every player, with the server key already derived from their shares
+fn setup_players() -> (Vec<ClientKey>, Vec<ServerKeyShare>) {
+    setup(&[0u8; 32]);
+    let cks = (0..TOTAL_USERS).map(|_| gen_client_key()).collect_vec();
+    let sks = cks
+        .iter()
+        .enumerate()
+        .map(|(user_id, ck)| gen_server_key_share(user_id, TOTAL_USERS, ck))
+        .collect_vec();
+    derive_server_key(&sks);
+    (cks, sks)
+}
+
+fn starting_state(cks: &[ClientKey]) -> GameStateEnc {
+    let coords = cks
+        .iter()
+        .enumerate()
+        .map(|(user_id, ck)| {
+            UserAction::set_starting_coord(ck, &(0, 0))
+                .unpack(user_id)
+                .into_word()
+        })
+        .collect();
+    let eggs = UserAction::init_game(&cks[0], &[false; chickens::BOARD_SIZE])
+        .unpack(0)
+        .into_word()
+        .expect("init game carries eggs");
+    GameStateEnc { coords, eggs }
+}
+
+fn bench_protocol(c: &mut Criterion) {
+    let (cks, sks) = setup_players();
+    let state = starting_state(&cks);
+    let output = CircuitOutput::new(get_user_cell(&state, 0));
+
+    let mut group = c.benchmark_group("protocol");
+    group.sample_size(10);
+
+    group.bench_function("gen_server_key_share", |b| {
+        b.iter(|| gen_server_key_share(0, TOTAL_USERS, &cks[0]))
+    });
+    group.bench_function("aggregate_server_key_shares", |b| {
+        b.iter(|| aggregate_server_key_shares(&sks))
+    });
+    group.bench_function("evaluate_move_player", |b| {
+        let action = UserAction::move_player(&cks[0], Direction::Down).unpack(0);
+        b.iter(|| evaluate_circuit(state.clone(), &[(0, action.clone())]))
+    });
+    group.bench_function("evaluate_get_cell", |b| b.iter(|| get_user_cell(&state, 0)));
+    group.bench_function("gen_decryption_share", |b| {
+        b.iter(|| with_thread_pool(0, || output.gen_decryption_share(&cks[0])))
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("msgpack");
+    group.sample_size(10);
+
+    let sks_bytes = msgpack::to_compact_vec(&sks[0]).unwrap();
+    group.bench_function("serialize_server_key_share", |b| {
+        b.iter(|| msgpack::to_compact_vec(&sks[0]).unwrap())
+    });
+    group.bench_function("deserialize_server_key_share", |b| {
+        b.iter(|| msgpack::from_slice::<ServerKeyShare>(&sks_bytes).unwrap())
+    });
+    let output_bytes = msgpack::to_compact_vec(&output).unwrap();
+    group.bench_function("serialize_circuit_output", |b| {
+        b.iter(|| msgpack::to_compact_vec(&output).unwrap())
+    });
+    group.bench_function("deserialize_circuit_output", |b| {
+        b.iter(|| msgpack::from_slice::<CircuitOutput>(&output_bytes).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_protocol);
+criterion_main!(benches);
@@ -0,0 +1,11 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+        // Key share chunks go from the transport to the decoder without a copy
+        tonic_build::configure()
+            .bytes([".chickens.Chunk"])
+            .compile_protos(&["proto/chickens.proto"], &["proto"])
+            .unwrap();
+    }
+}
@@ -0,0 +1,100 @@
+/*
+ * C ABI of the chickens client: keys, encrypted actions, decryption shares and decryption.
+ *
+ * The library doesn't talk to the server. Every payload is msgpack, as the HTTP API takes
+ * and answers it: POST bodies with `Content-Type: application/msgpack`, responses fetched
+ * with `Accept: application/msgpack`.
+ *
+ * Functions returning int return 0 on success and -1 on error, functions returning a handle
+ * return NULL on error. `chickens_last_error` then tells what went wrong.
+ * Buffers written to `out` are owned by the caller, free them with `chickens_buffer_free`.
+ * Handles are freed with their `_free` function. `threads` 0 uses every core.
+ * Enum arguments must be one of their values, anything else is undefined behavior.
+ */
+#ifndef CHICKENS_H
+#define CHICKENS_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct ChickensClientKey ChickensClientKey;
+
+/* Encrypted output of an FHE run */
+typedef struct ChickensOutput ChickensOutput;
+
+typedef struct {
+    uint8_t *data;
+    size_t len;
+} ChickensBuffer;
+
+typedef enum {
+    CHICKENS_UP = 0,
+    CHICKENS_DOWN = 1,
+    CHICKENS_LEFT = 2,
+    CHICKENS_RIGHT = 3,
+} ChickensDirection;
+
+/* Actions without an encrypted argument */
+typedef enum {
+    CHICKENS_LAY_EGG = 0,
+    CHICKENS_PICKUP_EGG = 1,
+    CHICKENS_GET_CELL = 2,
+    CHICKENS_DONE = 3,
+} ChickensAction;
+
+/* Message of the last error on this thread, NULL if none. Valid until the next error */
+const char *chickens_last_error(void);
+
+void chickens_buffer_free(ChickensBuffer buffer);
+
+/* Set up the 32 bytes common reference seed of GET /param. Once per process */
+int chickens_setup(const uint8_t *seed, size_t seed_len);
+
+/* Generate a client key, after chickens_setup */
+ChickensClientKey *chickens_client_key_new(void);
+void chickens_client_key_free(ChickensClientKey *ck);
+
+/* Body of POST /submit_sks, the user's share of the server key. Takes a while, on one thread */
+int chickens_sks_submission(const ChickensClientKey *ck, size_t user_id, size_t total_users,
+                            ChickensBuffer *out);
+
+/* Bodies of POST /setup_game/{user_id} */
+int chickens_init_game(const ChickensClientKey *ck, const bool *initial_eggs, size_t len,
+                       ChickensBuffer *out);
+int chickens_set_starting_coord(const ChickensClientKey *ck, uint8_t x, uint8_t y,
+                                ChickensBuffer *out);
+
+/* Bodies of POST /request_action/{user_id}, or of POST /done/{user_id} for CHICKENS_DONE */
+int chickens_move_player(const ChickensClientKey *ck, ChickensDirection direction,
+                         ChickensBuffer *out);
+int chickens_plain_action(ChickensAction action, ChickensBuffer *out);
+
+/* Decode the response of GET /fhe_output */
+ChickensOutput *chickens_output_decode(const uint8_t *data, size_t len);
+void chickens_output_free(ChickensOutput *output);
+
+/* The user's decryption share of the output, encoded as GET /decryption_share answers it */
+int chickens_decryption_share(const ChickensOutput *output, const ChickensClientKey *ck,
+                              size_t threads, ChickensBuffer *out);
+
+/* Body of POST /submit_decryption_share, from a share of chickens_decryption_share */
+int chickens_decryption_share_submission(size_t user_id, size_t output_id, const uint8_t *share,
+                                         size_t len, ChickensBuffer *out);
+
+/*
+ * Decrypt the output with the decryption shares of every user, in user id order.
+ * `out` gets one byte per bit of the output, 0 or 1.
+ */
+int chickens_decrypt(const ChickensOutput *output, const ChickensClientKey *ck,
+                     const ChickensBuffer *shares, size_t total_users, ChickensBuffer *out);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,312 @@
+//! C ABI of the client side of the protocol: keys, encrypted actions, decryption shares and
+//! decryption. Transport is left to the caller, payloads are msgpack buffers that go to or
+//! come from the HTTP API as they are. The contract of every function is in
+//! `include/chickens.h`.
+#![allow(clippy::missing_safety_doc)]
+
+use anyhow::{anyhow, bail, Error};
+use chickens::{
+    decryption_share_body, gen_client_key, gen_server_key_share, setup, sks_submission_body,
+    with_thread_pool, CircuitOutput, ClientKey, DecryptionShare, Direction, UserAction, UserId,
+};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::{ptr, slice};
+
+const OK: c_int = 0;
+const ERROR: c_int = -1;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Bytes owned by the library, freed with [`chickens_buffer_free`]
+#[repr(C)]
+pub struct ChickensBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+impl ChickensBuffer {
+    fn new(bytes: Vec<u8>) -> Self {
+        let bytes = Box::leak(bytes.into_boxed_slice());
+        Self {
+            data: bytes.as_mut_ptr(),
+            len: bytes.len(),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub enum ChickensDirection {
+    Up = 0,
+    Down = 1,
+    Left = 2,
+    Right = 3,
+}
+
+impl From<ChickensDirection> for Direction {
+    fn from(direction: ChickensDirection) -> Self {
+        match direction {
+            ChickensDirection::Up => Direction::Up,
+            ChickensDirection::Down => Direction::Down,
+            ChickensDirection::Left => Direction::Left,
+            ChickensDirection::Right => Direction::Right,
+        }
+    }
+}
+
+/// Actions without an encrypted argument
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub enum ChickensAction {
+    LayEgg = 0,
+    PickupEgg = 1,
+    GetCell = 2,
+    Done = 3,
+}
+
+pub struct ChickensClientKey(ClientKey);
+
+pub struct ChickensOutput(CircuitOutput);
+
+/// Runs `f`, turning its error or panic into the thread's last error
+fn guard<T>(f: impl FnOnce() -> Result<T, Error>) -> Option<T> {
+    let result = panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Err(anyhow!("Panicked: {message}"))
+    });
+    match result {
+        Ok(value) => Some(value),
+        Err(err) => {
+            let message = CString::new(err.to_string().replace('\0', "")).expect("no nul");
+            LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+            None
+        }
+    }
+}
+
+fn status(result: Option<()>) -> c_int {
+    result.map_or(ERROR, |_| OK)
+}
+
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> Result<&'a [u8], Error> {
+    match (data.is_null(), len) {
+        (true, 0) => Ok(&[]),
+        (true, _) => bail!("Null buffer of {len} bytes"),
+        (false, _) => Ok(slice::from_raw_parts(data, len)),
+    }
+}
+
+unsafe fn handle<'a, T>(handle: *const T) -> Result<&'a T, Error> {
+    handle.as_ref().ok_or(anyhow!("Null handle"))
+}
+
+unsafe fn write_out(out: *mut ChickensBuffer, bytes: Vec<u8>) -> Result<(), Error> {
+    if out.is_null() {
+        bail!("Null output buffer")
+    }
+    out.write(ChickensBuffer::new(bytes));
+    Ok(())
+}
+
+#[no_mangle]
+pub extern "C" fn chickens_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chickens_buffer_free(buffer: ChickensBuffer) {
+    if !buffer.data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+            buffer.data,
+            buffer.len,
+        )));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chickens_setup(seed: *const u8, seed_len: usize) -> c_int {
+    status(guard(|| {
+        let seed = bytes(seed, seed_len)?
+            .try_into()
+            .map_err(|_| anyhow!("Seed of {seed_len} bytes, expected 32"))?;
+        setup(&seed);
+        Ok(())
+    }))
+}
+
+#[no_mangle]
+pub extern "C" fn chickens_client_key_new() -> *mut ChickensClientKey {
+    guard(|| Ok(with_thread_pool(1, gen_client_key))).map_or(ptr::null_mut(), |ck| {
+        Box::into_raw(Box::new(ChickensClientKey(ck)))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chickens_client_key_free(ck: *mut ChickensClientKey) {
+    if !ck.is_null() {
+        drop(Box::from_raw(ck));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chickens_sks_submission(
+    ck: *const ChickensClientKey,
+    user_id: usize,
+    total_users: usize,
+    out: *mut ChickensBuffer,
+) -> c_int {
+    status(guard(|| {
+        let ck = &handle(ck)?.0;
+        let sks = gen_server_key_share(user_id, total_users, ck);
+        write_out(out, sks_submission_body(user_id, &sks)?)
+    }))
+}
+
+/// Encrypts on a pool thread, phantom-zone keeps the parameter set per thread
+unsafe fn encrypt_action<T: Serialize + Send>(
+    ck: *const ChickensClientKey,
+    out: *mut ChickensBuffer,
+    action: impl FnOnce(&ClientKey) -> Result<UserAction<T>, Error> + Send,
+) -> c_int {
+    status(guard(|| {
+        let ck = &handle(ck)?.0;
+        let action = with_thread_pool(1, || action(ck))?;
+        write_out(out, rmp_serde::to_vec(&action)?)
+    }))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chickens_init_game(
+    ck: *const ChickensClientKey,
+    initial_eggs: *const bool,
+    len: usize,
+    out: *mut ChickensBuffer,
+) -> c_int {
+    if initial_eggs.is_null() {
+        return status(guard(|| -> Result<(), Error> { bail!("Null eggs") }));
+    }
+    let initial_eggs = slice::from_raw_parts(initial_eggs, len);
+    encrypt_action(ck, out, |ck| Ok(UserAction::init_game(ck, initial_eggs)))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chickens_set_starting_coord(
+    ck: *const ChickensClientKey,
+    x: u8,
+    y: u8,
+    out: *mut ChickensBuffer,
+) -> c_int {
+    encrypt_action(ck, out, |ck| {
+        Ok(UserAction::set_starting_coord(ck, &(x, y)))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chickens_move_player(
+    ck: *const ChickensClientKey,
+    direction: ChickensDirection,
+    out: *mut ChickensBuffer,
+) -> c_int {
+    encrypt_action(ck, out, |ck| {
+        Ok(UserAction::move_player(ck, direction.into()))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chickens_plain_action(
+    action: ChickensAction,
+    out: *mut ChickensBuffer,
+) -> c_int {
+    status(guard(|| {
+        // Variants without a word encode the same whatever the word type
+        let action: UserAction<()> = match action {
+            ChickensAction::LayEgg => UserAction::LayEgg,
+            ChickensAction::PickupEgg => UserAction::PickupEgg,
+            ChickensAction::GetCell => UserAction::GetCell,
+            ChickensAction::Done => UserAction::Done,
+        };
+        write_out(out, rmp_serde::to_vec(&action)?)
+    }))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chickens_output_decode(
+    data: *const u8,
+    len: usize,
+) -> *mut ChickensOutput {
+    guard(|| Ok(rmp_serde::from_slice::<CircuitOutput>(bytes(data, len)?)?))
+        .map_or(ptr::null_mut(), |output| {
+            Box::into_raw(Box::new(ChickensOutput(output)))
+        })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chickens_output_free(output: *mut ChickensOutput) {
+    if !output.is_null() {
+        drop(Box::from_raw(output));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chickens_decryption_share(
+    output: *const ChickensOutput,
+    ck: *const ChickensClientKey,
+    threads: usize,
+    out: *mut ChickensBuffer,
+) -> c_int {
+    status(guard(|| {
+        let (output, ck) = (&handle(output)?.0, &handle(ck)?.0);
+        let share = with_thread_pool(threads, || output.gen_decryption_share(ck));
+        write_out(out, rmp_serde::to_vec(&share)?)
+    }))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chickens_decryption_share_submission(
+    user_id: UserId,
+    output_id: usize,
+    share: *const u8,
+    len: usize,
+    out: *mut ChickensBuffer,
+) -> c_int {
+    status(guard(|| {
+        let share = rmp_serde::from_slice(bytes(share, len)?)?;
+        write_out(out, decryption_share_body(user_id, output_id, share)?)
+    }))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn chickens_decrypt(
+    output: *const ChickensOutput,
+    ck: *const ChickensClientKey,
+    shares: *const ChickensBuffer,
+    total_users: usize,
+    out: *mut ChickensBuffer,
+) -> c_int {
+    status(guard(|| {
+        let (output, ck) = (&handle(output)?.0, &handle(ck)?.0);
+        let shares = match shares.is_null() {
+            true => bail!("Null shares"),
+            false => slice::from_raw_parts(shares, total_users),
+        };
+        let shares = shares
+            .iter()
+            .map(|share| Ok(rmp_serde::from_slice(bytes(share.data, share.len)?)?))
+            .collect::<Result<Vec<DecryptionShare>, Error>>()?;
+        let bits = with_thread_pool(1, || output.decrypt(ck, &shares));
+        write_out(out, bits.into_iter().map(u8::from).collect())
+    }))
+}
@@ -0,0 +1,142 @@
+//! The exported functions against a sim server, called the way a C client calls them.
+//! Run with `cargo test -p chickens-ffi --no-default-features --features sim`.
+#![cfg(feature = "sim")]
+
+use chickens::Param;
+use chickens_ffi::*;
+use rocket::http::{Accept, ContentType, Status};
+use rocket::local::asynchronous::Client;
+use rocket::tokio::time::sleep;
+use std::ffi::{c_int, CStr};
+use std::{ptr, slice, time::Duration};
+
+const TOTAL_USERS: usize = 4;
+
+/// Bytes written by `f` to its output buffer, which is freed
+fn out(f: impl FnOnce(*mut ChickensBuffer) -> c_int) -> Vec<u8> {
+    let mut buffer = ChickensBuffer {
+        data: ptr::null_mut(),
+        len: 0,
+    };
+    check(f(&mut buffer));
+    let bytes = unsafe { slice::from_raw_parts(buffer.data, buffer.len) }.to_vec();
+    unsafe { chickens_buffer_free(buffer) };
+    bytes
+}
+
+fn check(status: c_int) {
+    if status != 0 {
+        panic!("{:?}", unsafe { CStr::from_ptr(chickens_last_error()) });
+    }
+}
+
+async fn post(client: &Client, path: &str, body: Vec<u8>) {
+    let response = client
+        .post(path)
+        .header(ContentType::MsgPack)
+        .body(body)
+        .dispatch()
+        .await;
+    assert_eq!(response.status(), Status::Ok, "{path}");
+}
+
+async fn get(client: &Client, path: &str) -> Vec<u8> {
+    let response = client.get(path).header(Accept::MsgPack).dispatch().await;
+    assert_eq!(response.status(), Status::Ok, "{path}");
+    response.into_bytes().await.unwrap()
+}
+
+#[rocket::async_test]
+async fn plays_a_round() {
+    let client = Client::tracked(chickens::rocket()).await.unwrap();
+    let param: Param = rmp_serde::from_slice(&get(&client, "/param").await).unwrap();
+    check(unsafe { chickens_setup(param.seed.as_ptr(), param.seed.len()) });
+    // Only 32 bytes seeds
+    assert_eq!(unsafe { chickens_setup(param.seed.as_ptr(), 31) }, -1);
+    assert!(!chickens_last_error().is_null());
+
+    let keys = (0..TOTAL_USERS)
+        .map(|_| chickens_client_key_new())
+        .collect::<Vec<_>>();
+    assert!(keys.iter().all(|ck| !ck.is_null()));
+    for user_id in 0..TOTAL_USERS {
+        let response = client
+            .post("/register")
+            .body(format!("Player {user_id}"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+    }
+    for (user_id, ck) in keys.iter().enumerate() {
+        let body = out(|out| unsafe { chickens_sks_submission(*ck, user_id, TOTAL_USERS, out) });
+        post(&client, "/submit_sks", body).await;
+    }
+
+    let eggs = [false; 16];
+    let body = out(|out| unsafe { chickens_init_game(keys[0], eggs.as_ptr(), eggs.len(), out) });
+    post(&client, "/setup_game/0", body).await;
+    for (user_id, ck) in keys.iter().enumerate() {
+        let (x, y) = (user_id as u8, user_id as u8);
+        let body = out(|out| unsafe { chickens_set_starting_coord(*ck, x, y, out) });
+        post(&client, &format!("/setup_game/{user_id}"), body).await;
+    }
+
+    // Every direction encrypts, then player 0 lays an egg at (0, 0)
+    for direction in [
+        ChickensDirection::Up,
+        ChickensDirection::Down,
+        ChickensDirection::Left,
+        ChickensDirection::Right,
+    ] {
+        let body = out(|out| unsafe { chickens_move_player(keys[0], direction, out) });
+        assert!(!body.is_empty());
+    }
+    let body = out(|out| unsafe { chickens_plain_action(ChickensAction::LayEgg, out) });
+    post(&client, "/request_action/0", body).await;
+    loop {
+        let response = client.post("/run/0").dispatch().await;
+        if response
+            .into_string()
+            .await
+            .unwrap()
+            .contains("CompletedFhe")
+        {
+            break;
+        }
+        sleep(Duration::from_millis(20)).await
+    }
+
+    let output = get(&client, "/fhe_output").await;
+    let output = unsafe { chickens_output_decode(output.as_ptr(), output.len()) };
+    assert!(!output.is_null());
+    for (user_id, ck) in keys.iter().enumerate() {
+        let share = out(|out| unsafe { chickens_decryption_share(output, *ck, 1, out) });
+        let body = out(|out| unsafe {
+            chickens_decryption_share_submission(user_id, 0, share.as_ptr(), share.len(), out)
+        });
+        post(&client, "/submit_decryption_share", body).await;
+    }
+    let mut shares = vec![];
+    for user_id in 0..TOTAL_USERS {
+        shares.push(get(&client, &format!("/decryption_share/0/{user_id}")).await);
+    }
+    let shares = shares
+        .iter_mut()
+        .map(|share| ChickensBuffer {
+            data: share.as_mut_ptr(),
+            len: share.len(),
+        })
+        .collect::<Vec<_>>();
+    let cell =
+        out(|out| unsafe { chickens_decrypt(output, keys[0], shares.as_ptr(), TOTAL_USERS, out) });
+    assert_eq!(cell, [1, 0, 0, 0, 1]);
+
+    for user_id in 0..TOTAL_USERS {
+        let body = out(|out| unsafe { chickens_plain_action(ChickensAction::Done, out) });
+        post(&client, &format!("/done/{user_id}"), body).await;
+    }
+    unsafe {
+        chickens_output_free(output);
+        keys.into_iter().for_each(|ck| chickens_client_key_free(ck));
+    }
+}
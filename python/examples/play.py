@@ -0,0 +1,58 @@
+"""Play a few rounds with 4 players from one script, against a running server.
+
+    python examples/play.py http://0.0.0.0:5566
+"""
+
+import sys
+import time
+
+import chickens
+
+BOARD_SIZE = 16
+TOTAL_USERS = 4
+
+
+def play_round(client, keys, round, user_id, turn):
+    """`user_id` takes `turn`, every player shares the output and `user_id` decrypts their cell"""
+    turn(user_id)
+    while client.run(user_id) != "CompletedFhe":
+        time.sleep(0.1)
+
+    output = client.fhe_output()
+    for player, ck in enumerate(keys):
+        client.submit_decryption_share(player, round, output.decryption_share(ck))
+    shares = [client.decryption_share(round, player) for player in range(TOTAL_USERS)]
+    cell = output.decrypt(keys[user_id], shares)
+
+    for player in range(TOTAL_USERS):
+        client.done(player)
+    return cell
+
+
+def main(url):
+    client = chickens.Client(url)
+    client.setup()
+    keys = [chickens.ClientKey() for _ in range(TOTAL_USERS)]
+    for i in range(TOTAL_USERS):
+        client.register(f"player {i}")
+    for user_id, ck in enumerate(keys):
+        client.submit_sks(user_id, ck.server_key_share(user_id, TOTAL_USERS))
+
+    client.init_game(keys[0], 0, [False] * BOARD_SIZE)
+    for user_id, ck in enumerate(keys):
+        client.set_starting_coords(ck, user_id, user_id, user_id)
+
+    turns = [
+        (0, client.lay_egg),
+        (1, lambda user_id: client.move_player(keys[user_id], user_id, "up")),
+        (1, lambda user_id: client.move_player(keys[user_id], user_id, "left")),
+        (0, client.pickup_egg),
+    ]
+    for round, (user_id, turn) in enumerate(turns):
+        cell = play_round(client, keys, round, user_id, turn)
+        print(f"round {round}: player {user_id} sees {cell}")
+    print(client.dashboard())
+
+
+if __name__ == "__main__":
+    main(sys.argv[1])
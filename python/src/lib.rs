@@ -0,0 +1,243 @@
+//! Python bindings of the chickens client, for scripting games from notebooks.
+//! Requests block until the server answers, FHE work releases the GIL meanwhile.
+
+use anyhow::bail;
+use chickens::{
+    gen_client_key, gen_server_key_share, setup, thread_pool, with_thread_pool, Direction, UserId,
+};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use rayon::ThreadPool;
+use std::future::Future;
+use tokio::runtime::Runtime;
+
+/// Blocking client of a chickens server
+#[pyclass]
+struct Client {
+    client: chickens::WebClient,
+    runtime: Runtime,
+    /// One thread with the parameter set, requests are polled on it
+    pool: ThreadPool,
+}
+
+impl Client {
+    /// Runs a request to completion. Encrypting actions needs the parameter set, which
+    /// phantom-zone keeps per thread, so requests are polled on the client's pool thread.
+    fn block_on<R: Send>(
+        &self,
+        py: Python<'_>,
+        request: impl Future<Output = Result<R, chickens::Error>> + Send,
+    ) -> PyResult<R> {
+        let result = py.allow_threads(|| self.pool.install(|| self.runtime.block_on(request)));
+        Ok(result.map_err(anyhow::Error::from)?)
+    }
+}
+
+#[pymethods]
+impl Client {
+    #[new]
+    fn new(url: &str) -> PyResult<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        Ok(Self {
+            client: chickens::WebClient::new(url),
+            runtime,
+            pool: thread_pool(1),
+        })
+    }
+
+    /// Fetch the common reference seed and set it up for this process.
+    /// A process can only set up one seed, so it plays one game.
+    fn setup<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        let seed = self.block_on(py, self.client.get_seed())?;
+        setup(&seed);
+        Ok(PyBytes::new(py, &seed))
+    }
+
+    /// Register `name`, returns the user id
+    fn register(&self, py: Python<'_>, name: &str) -> PyResult<UserId> {
+        let user = self.block_on(py, self.client.register(name))?;
+        Ok(user.id)
+    }
+
+    /// Server state and registered users, as a dict
+    fn dashboard(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let dashboard = self.block_on(py, self.client.get_dashboard())?;
+        to_python(py, &dashboard)
+    }
+
+    fn submit_sks(&self, py: Python<'_>, user_id: UserId, sks: &ServerKeyShare) -> PyResult<()> {
+        self.block_on(py, self.client.submit_sks(user_id, &sks.0))?;
+        Ok(())
+    }
+
+    /// Lay out the eggs, one bool per cell, row by row
+    fn init_game(
+        &self,
+        py: Python<'_>,
+        ck: &ClientKey,
+        user_id: UserId,
+        initial_eggs: Vec<bool>,
+    ) -> PyResult<()> {
+        self.block_on(py, self.client.init_game(&ck.0, user_id, &initial_eggs))?;
+        Ok(())
+    }
+
+    fn set_starting_coords(
+        &self,
+        py: Python<'_>,
+        ck: &ClientKey,
+        user_id: UserId,
+        x: u8,
+        y: u8,
+    ) -> PyResult<()> {
+        let coords = (x, y);
+        self.block_on(py, self.client.set_starting_coords(&ck.0, user_id, &coords))?;
+        Ok(())
+    }
+
+    /// Move one cell `up`, `down`, `left` or `right`
+    fn move_player(
+        &self,
+        py: Python<'_>,
+        ck: &ClientKey,
+        user_id: UserId,
+        direction: &str,
+    ) -> PyResult<()> {
+        let direction = parse_direction(direction)?;
+        self.block_on(py, self.client.move_player(&ck.0, user_id, direction))?;
+        Ok(())
+    }
+
+    fn lay_egg(&self, py: Python<'_>, user_id: UserId) -> PyResult<()> {
+        self.block_on(py, self.client.lay_egg(user_id))?;
+        Ok(())
+    }
+
+    fn pickup_egg(&self, py: Python<'_>, user_id: UserId) -> PyResult<()> {
+        self.block_on(py, self.client.pickup_egg(user_id))?;
+        Ok(())
+    }
+
+    fn get_cell(&self, py: Python<'_>, user_id: UserId) -> PyResult<()> {
+        self.block_on(py, self.client.get_cell(user_id))?;
+        Ok(())
+    }
+
+    /// Trigger the FHE run, or check on it. Returns the server state, `CompletedFhe` once done
+    fn run(&self, py: Python<'_>, user_id: UserId) -> PyResult<String> {
+        let state = self.block_on(py, self.client.trigger_fhe_run(user_id))?;
+        Ok(format!("{:?}", state))
+    }
+
+    /// Progress of the FHE run, as a dict
+    fn fhe_status(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let status = self.block_on(py, self.client.get_fhe_status())?;
+        to_python(py, &status)
+    }
+
+    fn fhe_output(&self, py: Python<'_>) -> PyResult<Output> {
+        let output = self.block_on(py, self.client.get_fhe_output())?;
+        Ok(Output(output))
+    }
+
+    fn submit_decryption_share(
+        &self,
+        py: Python<'_>,
+        user_id: UserId,
+        output_id: usize,
+        share: Vec<u64>,
+    ) -> PyResult<()> {
+        let share = (output_id, share);
+        self.block_on(py, self.client.submit_decryption_share(user_id, &share))?;
+        Ok(())
+    }
+
+    fn decryption_share(
+        &self,
+        py: Python<'_>,
+        output_id: usize,
+        user_id: UserId,
+    ) -> PyResult<Vec<u64>> {
+        self.block_on(py, self.client.get_decryption_share(output_id, user_id))
+    }
+
+    /// Done with the round's output, the next round starts once every user is
+    fn done(&self, py: Python<'_>, user_id: UserId) -> PyResult<()> {
+        self.block_on(py, self.client.done(user_id))?;
+        Ok(())
+    }
+}
+
+/// A user's secret key. Generate it after [`Client::setup`]
+#[pyclass]
+struct ClientKey(chickens::ClientKey);
+
+#[pymethods]
+impl ClientKey {
+    #[new]
+    fn new(py: Python<'_>) -> Self {
+        Self(py.allow_threads(|| with_thread_pool(1, gen_client_key)))
+    }
+
+    /// The share of the server key this user contributes. Takes a while with FHE, on one
+    /// thread
+    fn server_key_share(
+        &self,
+        py: Python<'_>,
+        user_id: UserId,
+        total_users: usize,
+    ) -> ServerKeyShare {
+        let sks = py.allow_threads(|| gen_server_key_share(user_id, total_users, &self.0));
+        ServerKeyShare(sks)
+    }
+}
+
+#[pyclass]
+struct ServerKeyShare(chickens::ServerKeyShare);
+
+/// Encrypted output of an FHE run
+#[pyclass]
+struct Output(chickens::CircuitOutput);
+
+#[pymethods]
+impl Output {
+    /// This user's decryption share of the output
+    #[pyo3(signature = (ck, threads = 0))]
+    fn decryption_share(&self, py: Python<'_>, ck: &ClientKey, threads: usize) -> Vec<u64> {
+        py.allow_threads(|| with_thread_pool(threads, || self.0.gen_decryption_share(&ck.0)))
+    }
+
+    /// Decrypt with the decryption shares of every user, in user id order
+    fn decrypt(&self, py: Python<'_>, ck: &ClientKey, shares: Vec<Vec<u64>>) -> Vec<bool> {
+        py.allow_threads(|| with_thread_pool(1, || self.0.decrypt(&ck.0, &shares)))
+    }
+}
+
+fn parse_direction(direction: &str) -> anyhow::Result<Direction> {
+    Ok(match direction {
+        "up" => Direction::Up,
+        "down" => Direction::Down,
+        "left" => Direction::Left,
+        "right" => Direction::Right,
+        _ => bail!("Invalid direction {direction}, expect up, down, left or right"),
+    })
+}
+
+/// Converts through json, the shapes are the ones the server answers
+fn to_python(py: Python<'_>, value: &impl serde::Serialize) -> PyResult<PyObject> {
+    let json = serde_json::to_string(value).map_err(anyhow::Error::from)?;
+    let value = py.import("json")?.call_method1("loads", (json,))?;
+    Ok(value.unbind())
+}
+
+#[pymodule]
+#[pyo3(name = "chickens")]
+fn chickens_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Client>()?;
+    m.add_class::<ClientKey>()?;
+    m.add_class::<ServerKeyShare>()?;
+    m.add_class::<Output>()?;
+    Ok(())
+}
@@ -0,0 +1,93 @@
+"""Smoke test of the bindings: a sim server in a subprocess, 4 players from this process.
+
+Build the module and the server with the `sim` feature, then run from `python/`
+
+    maturin develop --no-default-features --features sim
+    python -m unittest discover tests
+"""
+
+import os
+import socket
+import subprocess
+import time
+import unittest
+from pathlib import Path
+
+import chickens
+
+ROOT = Path(__file__).resolve().parents[2]
+BOARD_SIZE = 16
+TOTAL_USERS = 4
+
+
+def free_port():
+    with socket.socket() as sock:
+        sock.bind(("127.0.0.1", 0))
+        return sock.getsockname()[1]
+
+
+class SimGame(unittest.TestCase):
+    def setUp(self):
+        port = free_port()
+        env = dict(os.environ, ROCKET_ADDRESS="127.0.0.1", ROCKET_PORT=str(port))
+        # Build errors are on stderr, the server's logs on stdout
+        server = subprocess.Popen(
+            ["cargo", "run", "--no-default-features", "--features", "sim", "--bin", "server"],
+            cwd=ROOT,
+            env=env,
+            stdout=subprocess.DEVNULL,
+        )
+        self.addCleanup(server.wait)
+        self.addCleanup(server.kill)
+        self.client = chickens.Client(f"http://127.0.0.1:{port}")
+
+    def wait_for_server(self):
+        """The server may still be building"""
+        deadline = time.monotonic() + 600
+        while True:
+            try:
+                return self.client.setup()
+            except Exception:
+                if time.monotonic() > deadline:
+                    raise
+                time.sleep(0.5)
+
+    def play_round(self, keys, round, user_id, turn):
+        client = self.client
+        turn(user_id)
+        while client.run(user_id) != "CompletedFhe":
+            time.sleep(0.1)
+
+        output = client.fhe_output()
+        for player, ck in enumerate(keys):
+            client.submit_decryption_share(player, round, output.decryption_share(ck))
+        shares = [client.decryption_share(round, player) for player in range(TOTAL_USERS)]
+        cell = output.decrypt(keys[user_id], shares)
+
+        for player in range(TOTAL_USERS):
+            client.done(player)
+        return cell
+
+    def test_plays_a_game(self):
+        client = self.client
+        self.assertEqual(len(self.wait_for_server()), 32)
+        keys = [chickens.ClientKey() for _ in range(TOTAL_USERS)]
+        for i in range(TOTAL_USERS):
+            self.assertEqual(client.register(f"player {i}"), i)
+        for user_id, ck in enumerate(keys):
+            client.submit_sks(user_id, ck.server_key_share(user_id, TOTAL_USERS))
+
+        client.init_game(keys[0], 0, [False] * BOARD_SIZE)
+        for user_id, ck in enumerate(keys):
+            client.set_starting_coords(ck, user_id, user_id, user_id)
+
+        # Player 0 is alone at (0, 0), with the egg they laid and then without it
+        cell = self.play_round(keys, 0, 0, client.lay_egg)
+        self.assertEqual(cell, [True, False, False, False, True])
+        cell = self.play_round(keys, 1, 0, client.pickup_egg)
+        self.assertEqual(cell, [True, False, False, False, False])
+        self.assertEqual(len(client.dashboard()["users"]), TOTAL_USERS)
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,307 @@
+use anyhow::{anyhow, bail, Error};
+use chickens::{rocket, Action, Direction, GameClient, GameConfig, Phase, UserId, WebClient};
+use clap::Parser;
+use itertools::Itertools;
+use rand::{rngs::StdRng, thread_rng, Rng, RngCore, SeedableRng};
+use rocket::{config::LogLevel, fairing::AdHoc};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tabled::{Table, Tabled};
+use tokio::{sync::oneshot, time::sleep};
+
+/// Play a whole game locally: the server runs in-process, simulated players talk to it
+/// over HTTP. Build with `--features sim` to skip FHE.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    /// Rounds of random turns to play
+    #[arg(long, default_value_t = 3, conflicts_with = "script")]
+    rounds: usize,
+    /// Turns to play instead, one per round, as `player:action` with action one of
+    /// up, down, left, right, lay, pickup, get. E.g. `--script 0:lay 1:up`
+    #[arg(long, num_args = 1..)]
+    script: Vec<ScriptedTurn>,
+    /// Seed of the starting coordinates and random turns, random if not set
+    #[arg(long)]
+    seed: Option<u64>,
+    /// Threads for decryption share generation, 0 uses every core
+    #[arg(long, default_value_t = 0)]
+    threads: usize,
+}
+
+const ACTIONS: [Action; 7] = [
+    Action::Move(Direction::Up),
+    Action::Move(Direction::Down),
+    Action::Move(Direction::Left),
+    Action::Move(Direction::Right),
+    Action::LayEgg,
+    Action::PickupEgg,
+    Action::GetCell,
+];
+
+#[derive(Clone, Copy, Debug)]
+struct ScriptedTurn {
+    user_id: UserId,
+    action: Action,
+}
+
+impl FromStr for ScriptedTurn {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (user_id, action) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("expect `player:action`, got {s}"))?;
+        let user_id = user_id.parse::<UserId>()?;
+        let action = match action {
+            "up" => Action::Move(Direction::Up),
+            "down" => Action::Move(Direction::Down),
+            "left" => Action::Move(Direction::Left),
+            "right" => Action::Move(Direction::Right),
+            "lay" => Action::LayEgg,
+            "pickup" => Action::PickupEgg,
+            "get" => Action::GetCell,
+            _ => bail!("unknown action {action}"),
+        };
+        Ok(Self { user_id, action })
+    }
+}
+
+/// Elapsed time per phase, summed over rounds, in the order phases first ran
+#[derive(Default)]
+struct Timings(Vec<(&'static str, Duration, usize)>);
+
+impl Timings {
+    fn add(&mut self, phase: &'static str, start: Instant) {
+        let elapsed = start.elapsed();
+        match self.0.iter_mut().find(|(p, ..)| *p == phase) {
+            Some((_, total, runs)) => {
+                *total += elapsed;
+                *runs += 1;
+            }
+            None => self.0.push((phase, elapsed, 1)),
+        }
+    }
+
+    fn print(&self) {
+        #[derive(Tabled)]
+        struct Row {
+            phase: &'static str,
+            runs: usize,
+            total: String,
+            mean: String,
+        }
+        let rows = self.0.iter().map(|(phase, total, runs)| Row {
+            phase,
+            runs: *runs,
+            total: format!("{:.2?}", total),
+            mean: format!("{:.2?}", *total / *runs as u32),
+        });
+        println!("{}", Table::new(rows));
+    }
+}
+
+#[rocket::main]
+async fn main() {
+    let cli = Cli::parse();
+    if let Err(err) = run(cli).await {
+        println!("❌ Error: {:?}", err);
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), Error> {
+    let seed = cli.seed.unwrap_or_else(|| thread_rng().next_u64());
+    let mut rng = StdRng::seed_from_u64(seed);
+    let backend = if cfg!(feature = "sim") {
+        "plaintext sim"
+    } else {
+        "FHE"
+    };
+
+    let url = launch_server().await?;
+    let config = WebClient::new(&url).get_param().await?.config;
+    println!("Simulating {config} with {backend}, seed {seed}");
+    if let Some(turn) = cli
+        .script
+        .iter()
+        .find(|turn| turn.user_id >= config.players())
+    {
+        bail!(
+            "player should be in [0, {}), got {}",
+            config.players(),
+            turn.user_id
+        )
+    }
+    let mut timings = Timings::default();
+
+    let start = Instant::now();
+    let mut games = vec![];
+    for i in 0..config.players() {
+        let mut game =
+            GameClient::new(WebClient::new(&url), &format!("Player {i}")).with_threads(cli.threads);
+        game.register(None).await?;
+        games.push(game);
+    }
+    wait_for(&mut games, Phase::RegistrationClosed).await?;
+    timings.add("Register", start);
+
+    for game in games.iter_mut() {
+        let start = Instant::now();
+        game.submit_key_share(None).await?;
+        timings.add("Server key share", start);
+    }
+    wait_for(&mut games, Phase::ReadyForSetup).await?;
+
+    let start = Instant::now();
+    let dim = config.board_dim() as u8;
+    for game in games.iter_mut() {
+        let (x, y) = (rng.gen_range(0..dim), rng.gen_range(0..dim));
+        game.setup_game(x, y).await?;
+    }
+    wait_for(&mut games, Phase::ReadyForAction).await?;
+    timings.add("Setup game", start);
+
+    let turns = if cli.script.is_empty() {
+        (0..cli.rounds)
+            .map(|_| ScriptedTurn {
+                user_id: rng.gen_range(0..config.players()),
+                action: ACTIONS[rng.gen_range(0..ACTIONS.len())],
+            })
+            .collect_vec()
+    } else {
+        cli.script
+    };
+    let rounds = turns.len();
+    // Every player looks at their cell in the end, to reveal the final board
+    let reveal = (0..config.players()).map(|user_id| ScriptedTurn {
+        user_id,
+        action: Action::GetCell,
+    });
+    // The cell each player decrypted last
+    let mut cells = vec![None; config.players()];
+    for (round, ScriptedTurn { user_id, action }) in turns.into_iter().chain(reveal).enumerate() {
+        if round == rounds {
+            println!("Reveal the final board");
+        }
+        println!("Round {round}: player {user_id} plays {action:?}");
+        let over = play_round(&mut games, user_id, action, &mut timings).await?;
+        cells[user_id] = games[user_id].output().map(<[bool]>::to_vec);
+        if over {
+            println!("Game over after {} rounds", round + 1);
+            break;
+        }
+    }
+
+    print_board(&config, &games, &cells);
+    timings.print();
+    Ok(())
+}
+
+/// Serve the chickens rocket on a free local port, in this process. Answers its url.
+async fn launch_server() -> Result<String, Error> {
+    let rocket = rocket();
+    let figment = rocket
+        .figment()
+        .clone()
+        .merge(("address", "127.0.0.1"))
+        .merge(("port", 0))
+        .merge(("log_level", LogLevel::Critical));
+    let (sender, receiver) = oneshot::channel();
+    let rocket = rocket
+        .configure(figment)
+        .attach(AdHoc::on_liftoff("Report port", |rocket| {
+            Box::pin(async move {
+                sender.send(rocket.config().port).ok();
+            })
+        }));
+    tokio::spawn(rocket.launch());
+    let port = receiver.await?;
+    Ok(format!("http://127.0.0.1:{port}"))
+}
+
+/// Refresh every player until they reached `phase`
+async fn wait_for(games: &mut [GameClient], phase: Phase) -> Result<(), Error> {
+    for game in games.iter_mut() {
+        while game.refresh().await? != phase {
+            sleep(Duration::from_millis(20)).await
+        }
+    }
+    Ok(())
+}
+
+/// Play one round where `user_id` takes `action`. Answers whether the game is over.
+async fn play_round(
+    games: &mut [GameClient],
+    user_id: UserId,
+    action: Action,
+    timings: &mut Timings,
+) -> Result<bool, Error> {
+    let start = Instant::now();
+    if !games[user_id].act(action).await? {
+        bail!("player {user_id} wasn't the first to act")
+    }
+    timings.add("Submit action", start);
+
+    let start = Instant::now();
+    wait_for(games, Phase::CompletedFhe).await?;
+    timings.add("FHE run", start);
+
+    let start = Instant::now();
+    for game in games.iter_mut() {
+        game.decrypt().await?;
+    }
+    timings.add("Decryption shares", start);
+
+    let start = Instant::now();
+    wait_for(&mut games[user_id..=user_id], Phase::Decrypted).await?;
+    timings.add("Decrypt", start);
+
+    for game in games.iter_mut() {
+        game.finish_round().await?;
+    }
+    for game in games.iter_mut() {
+        loop {
+            match game.refresh().await? {
+                Phase::ReadyForAction => break,
+                Phase::GameOver => return Ok(true),
+                _ => sleep(Duration::from_millis(20)).await,
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Cells the players decrypted last, at their coordinates. The rest stays hidden.
+fn print_board(config: &GameConfig, games: &[GameClient], cells: &[Option<Vec<bool>>]) {
+    let mut board_cells: HashMap<(u8, u8), &[bool]> = HashMap::new();
+    for (game, cell) in games.iter().zip(cells) {
+        if let (Some(view), Some(cell)) = (game.view(), cell) {
+            board_cells.insert(view.my_coord(), cell);
+        }
+    }
+
+    let (dim, players) = (config.board_dim() as u8, config.players());
+    let board = (0..dim).map(|x| {
+        (0..dim)
+            .map(|y| match board_cells.get(&(x, y)) {
+                Some(cell) => {
+                    let mut text = (0..players)
+                        .filter(|user| cell[*user])
+                        .map(|user| format!("(🐓{})", user))
+                        .join("");
+                    if cell[players] {
+                        text.push('🥚');
+                    }
+                    text
+                }
+                None => "🌫️".to_string(),
+            })
+            .collect_vec()
+    });
+    println!("----------------Final Board-------------------");
+    println!("{}", Table::from_iter(board));
+}
@@ -1,203 +1,189 @@
 use anyhow::{anyhow, bail, Error};
+#[cfg(feature = "keyring")]
+use chickens::SecretStore;
 use chickens::{
-    setup, CircuitOutput, DecryptionSharesMap, Direction, GameStateLocalView, ServerState, UserId,
-    WebClient, BOARD_SIZE,
+    derive_server_key, evaluate_circuit, gen_client_key, gen_server_key_share, get_user_cell,
+    setup, time, with_thread_pool, Action, CircuitOutput, Direction, GameClient, GameStateEnc,
+    Phase, Session, UserAction, UserId, WebClient, BOARD_SIZE, TOTAL_USERS,
 };
-use clap::{command, Parser};
+use clap::{command, Parser, Subcommand};
 use itertools::Itertools;
-use phantom_zone::{gen_client_key, gen_server_key_share, ClientKey};
+use rand::{thread_rng, RngCore};
+use rocket::serde::msgpack;
 use rustyline::{error::ReadlineError, DefaultEditor};
-use std::{collections::HashMap, fmt::Display};
+use std::path::{Path, PathBuf};
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(
+    version,
+    about,
+    long_about = None,
+    subcommand_negates_reqs = true,
+    args_conflicts_with_subcommands = true
+)]
 struct Cli2 {
+    #[command(subcommand)]
+    command: Option<Command>,
     /// Optional name to operate on
-    name: String,
-    url: String,
+    #[arg(required = true)]
+    name: Option<String>,
+    #[arg(required = true)]
+    url: Option<String>,
+    /// Keep the registration in the OS keyring and the client key in the session file,
+    /// encrypted under a passphrase in the keyring, so a restarted client resumes its game
+    #[cfg(feature = "keyring")]
+    #[arg(long)]
+    keyring: bool,
+    /// Session file of --keyring, `<NAME>.session` by default
+    #[cfg(feature = "keyring")]
+    #[arg(long, value_name = "FILE")]
+    session: Option<PathBuf>,
+    /// Remove the keyring entries and the session file of this name on this server, then exit
+    #[cfg(feature = "keyring")]
+    #[arg(long)]
+    clear_keyring: bool,
+    /// Record every exchange with the server to a fixture file
+    #[arg(long, value_name = "FIXTURE", conflicts_with = "replay")]
+    record: Option<PathBuf>,
+    /// Replay a recorded fixture file instead of talking to the server
+    #[arg(long, value_name = "FIXTURE")]
+    replay: Option<PathBuf>,
+    /// Threads for decryption share generation, 0 uses every core
+    #[arg(long, default_value_t = 0, global = true)]
+    threads: usize,
 }
 
-enum State {
-    Init(StateInit),
-    Setup(StateSetup),
-    ConcludedRegistration(Registration),
-    SubmittedSks(Registration),
-    ConcludedSubmitSks(Registration),
-    InitGame(StateGame),
-    SetupGame(StateGame), // set starting coordinates
-    ConcludedSetupGame(StateGame),
-    GameAction(StateGameAction),
-    CompletedFhe(StateGameAction),
-    DownloadedOutput(StateDownloadedOutput),
-    ConcludedDecryptionSubmission(StateDownloadedOutput),
-    Decrypted(StateDecrypted),
-    NewRound(StateGame),
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Time a round played locally by 4 players, no server needed
+    Bench,
 }
 
-impl Display for State {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let label = match self {
-            State::Init(_) => "Initialization",
-            State::Setup(_) => "Setup",
-            State::ConcludedRegistration(_) => "Concluded Registration",
-            State::SubmittedSks(_) => "Submitted Server Key Share",
-            State::ConcludedSubmitSks(_) => "Concluded SubmitSks",
-            State::InitGame(_) => "Init game",
-            State::SetupGame(_) => "Setup game",
-            State::ConcludedSetupGame(_) => "Concluded setup game",
-            State::GameAction(_) => "A player took an action",
-            State::CompletedFhe(_) => "Completed FHE",
-            State::DownloadedOutput(_) => "Downloaded Output",
-            State::ConcludedDecryptionSubmission(_) => "Concluded decryption share submission",
-            State::Decrypted(_) => "Decrypted",
-            State::NewRound(_) => "Ready for another action",
-        };
-        write!(f, "{{{{ {} }}}}", label)
-    }
-}
-
-impl State {
-    fn print_status_update(&self) {
-        let msg = match self {
-            State::Init(StateInit { name, client }) => {
-                format!("Hi {}, we just connected to server {}.", name, client.url())
-            }
-            State::Setup(StateSetup { .. }) => "✅ Setup completed!".to_string(),
-            State::ConcludedRegistration(_) => "✅ Got 4 players!".to_string(),
-            State::SubmittedSks(_) => "✅ Server key share submitted!".to_string(),
-            State::ConcludedSubmitSks(_) => "✅ Got all 4 server key shares!".to_string(),
-            State::InitGame(_) => "✅ New game start!".to_string(),
-            State::SetupGame(_) => "✅ Set starting coordinates!".to_string(),
-            State::ConcludedSetupGame(_) => "✅ Ready for an action!".to_string(),
-            State::GameAction(_) => "✅ A player took an action!".to_string(),
-            State::CompletedFhe(_) => "✅ Completed FHE!".to_string(),
-            State::DownloadedOutput(_) => "✅ FHE output downloaded!".to_string(),
-            State::ConcludedDecryptionSubmission(_) => {
-                "✅ Decryption shares submitted!".to_string()
-            }
-            State::Decrypted(_) => "✅ FHE output decrypted!".to_string(),
-            State::NewRound(_) => "✅ Ready for another action!".to_string(),
-        };
-        println!("{}", msg)
-    }
-
-    fn print_instruction(&self) {
-        let msg = match self {
-            State::Setup(_) => "We need 4 players. Enter `next` to check if we can proceed.",
-            State::SubmittedSks(_) =>
-                "Server needs to get all 4 server key shares. Enter `next` to check if we can proceed.",
-            State::ConcludedSubmitSks(_) => "Enter `next` to start a new game.",
-            State::InitGame(_) => "Enter `next ${x} ${y}` with your starting coordinates (x, y).\n The board is 4 x 4, so x, y has to be in the range [0, 3]." ,
-            State::SetupGame(_) => "Wait for every user to set starting coordinates. Enter `next` to check if we can proceed.",
-            State::ConcludedSetupGame(_) => "Enter one of the commands {`move up` | `move down` | `move left` | `move right` | `lay` | `pickup`}",
-            State::GameAction(_) => "Server running FHE. Enter `next` to check if it completed",
-            State::DownloadedOutput(_) => "Wait for other players to submit decryption shares. Enter `next` to check if we can proceed.",
-            State::Decrypted(_) => "Enter `next` to take another action; Or exit with `CTRL-D`",
-            State::NewRound(_) => "Wait for other users to be ready. Enter `next` check if we can proceed.",
-            _ => "Enter `next` to continue",
-        };
-        println!("👇 {}", msg)
-    }
-}
-
-struct StateInit {
-    name: String,
-    client: WebClient,
-}
-
-struct StateSetup {
-    name: String,
-    client: WebClient,
-    ck: ClientKey,
-    user_id: UserId,
-}
-
-struct Registration {
-    name: String,
-    client: WebClient,
-    ck: ClientKey,
-    user_id: UserId,
-    names: Vec<String>,
-}
-
-struct StateGame {
-    name: String,
-    client: WebClient,
-    ck: ClientKey,
-    user_id: UserId,
-    names: Vec<String>,
-    view: GameStateLocalView,
-    round: usize,
-}
-
-struct StateGameAction {
-    name: String,
-    client: WebClient,
-    ck: ClientKey,
-    user_id: UserId,
-    names: Vec<String>,
-    view: GameStateLocalView,
-    is_my_action: bool, // whether I took the action, or other players took the action
-    round: usize,
-}
-
-struct StateDownloadedOutput {
-    #[allow(dead_code)]
-    name: String,
-    client: WebClient,
-    ck: ClientKey,
-    user_id: UserId,
-    names: Vec<String>,
-    fhe_out: CircuitOutput,
-    shares: DecryptionSharesMap,
-    view: GameStateLocalView,
-    is_my_action: bool, // only decrypt if it is my action
-    round: usize,
+fn print_status_update(game: &GameClient) {
+    let config = game.config();
+    let msg = match game.phase() {
+        Phase::Unregistered => format!(
+            "Hi {}, we just connected to server {}.",
+            game.name(),
+            game.client().url()
+        ),
+        Phase::Registered => format!("✅ Setup completed! We play {}.", config),
+        Phase::RegistrationClosed => format!("✅ Got {} players!", config.players()),
+        Phase::SubmittedKeyShare => "✅ Server key share submitted!".to_string(),
+        Phase::ReadyForSetup => format!("✅ Got all {} server key shares!", config.players()),
+        Phase::SetupGame => "✅ Set starting coordinates!".to_string(),
+        Phase::ReadyForAction => match config.round_timer() {
+            Some(timer) => format!("✅ Ready for an action! You have {}s.", timer.as_secs()),
+            None => "✅ Ready for an action!".to_string(),
+        },
+        Phase::RunningFhe => "✅ A player took an action!".to_string(),
+        Phase::CompletedFhe => "✅ Completed FHE!".to_string(),
+        Phase::AwaitingShares => "✅ FHE output downloaded!".to_string(),
+        Phase::Decrypted => "✅ FHE output decrypted!".to_string(),
+        Phase::NewRound => "✅ Ready for another action!".to_string(),
+        Phase::GameOver => "🏁 Game over!".to_string(),
+    };
+    println!("{}", msg)
 }
 
-struct StateDecrypted {
-    #[allow(dead_code)]
-    name: String,
-    client: WebClient,
-    ck: ClientKey,
-    user_id: UserId,
-    names: Vec<String>,
-    view: GameStateLocalView,
-    is_my_action: bool,
-    decrypted_output: Option<Vec<bool>>,
-    round: usize,
+fn print_instruction(game: &GameClient) {
+    let config = game.config();
+    let msg = match game.phase() {
+        Phase::Registered => format!("We need {} players. Enter `next` to check if we can proceed.", config.players()),
+        Phase::RegistrationClosed =>
+            "Enter `next` to submit your server key share. Low on memory? Enter `next ${file}` to save the share to a file and upload it from there.".to_string(),
+        Phase::SubmittedKeyShare =>
+            format!("Server needs to get all {} server key shares. Enter `next` to check if we can proceed.", config.players()),
+        Phase::ReadyForSetup => {
+            let dim = config.board_dim();
+            format!("Enter `next ${{x}} ${{y}}` with your starting coordinates (x, y) to start a new game.\n The board is {dim} x {dim}, so x, y has to be in the range [0, {}].", dim - 1)
+        }
+        Phase::SetupGame => "Wait for every user to set starting coordinates. Enter `next` to check if we can proceed.".to_string(),
+        Phase::ReadyForAction => "Enter one of the commands {`move up` | `move down` | `move left` | `move right` | `lay` | `pickup`}, or `next` to check if another player took one".to_string(),
+        Phase::RunningFhe => "Server running FHE. Enter `next` to check if it completed".to_string(),
+        Phase::AwaitingShares => "Wait for other players to submit decryption shares. Enter `next` to check if we can proceed.".to_string(),
+        Phase::Decrypted => "Enter `next` to take another action; Or exit with `CTRL-D`".to_string(),
+        Phase::NewRound => "Wait for other users to be ready. Enter `next` check if we can proceed.".to_string(),
+        Phase::GameOver => "That was the last round, exit with `CTRL-D`".to_string(),
+        _ => "Enter `next` to continue".to_string(),
+    };
+    println!("👇 {}", msg)
 }
 
 #[tokio::main]
 async fn main() {
     let cli = Cli2::parse();
-    let name = cli.name;
-    let url: String = cli.url;
+    let threads = cli.threads;
+    if let Some(Command::Bench) = cli.command {
+        bench(threads);
+        return;
+    }
+    let name = cli.name.expect("required without a subcommand");
+    let url: String = cli.url.expect("required without a subcommand");
+
+    #[cfg(feature = "keyring")]
+    let session = cli
+        .session
+        .unwrap_or_else(|| PathBuf::from(format!("{name}.session")));
+    #[cfg(feature = "keyring")]
+    if cli.clear_keyring {
+        let cleared =
+            SecretStore::new(&name, &url).clear().and_then(|()| {
+                match std::fs::remove_file(&session) {
+                    Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+                    _ => Ok(()),
+                }
+            });
+        match cleared {
+            Ok(()) => println!("✅ Cleared keyring entries of {} on {}", name, url),
+            Err(err) => println!("❌ Error: {:?}", err),
+        }
+        return;
+    }
+    #[cfg(feature = "keyring")]
+    let session = cli.keyring.then_some(session);
+    #[cfg(not(feature = "keyring"))]
+    let session: Option<PathBuf> = None;
 
     let mut rl = DefaultEditor::new().unwrap();
-    let client = WebClient::new(&url);
-    let mut state = State::Init(StateInit { name, client });
-    println!("{}", state);
-    state.print_status_update();
-    state.print_instruction();
+    let client = match (cli.record, cli.replay) {
+        (Some(fixture), _) => WebClient::record(WebClient::new(&url), &fixture),
+        (_, Some(fixture)) => WebClient::replay(&fixture),
+        (None, None) => Ok(WebClient::new(&url)),
+    };
+    let client = match client {
+        Ok(client) => client,
+        Err(err) => {
+            println!("❌ Error: {:?}", err);
+            return;
+        }
+    };
+    let mut game = GameClient::new(client, &name).with_threads(threads);
+    println!("{}", game.phase());
+    print_status_update(&game);
+    print_instruction(&game);
     loop {
         let readline = rl.readline(">> ");
         match readline {
             Ok(line) => {
                 rl.add_history_entry(line.as_str()).unwrap();
-                state = match run(state, line.as_str()).await {
-                    Ok(state) => {
-                        println!("{}", state);
-                        state.print_status_update();
-                        state
+                match run(&mut game, line.as_str(), session.as_deref()).await {
+                    Ok(()) => {
+                        println!("{}", game.phase());
+                        print_status_update(&game);
+                        if let (Some(path), Some(saved)) = (&session, game.session()) {
+                            let url = game.client().url();
+                            if let Err(err) = store_session(game.name(), &url, path, &saved) {
+                                println!("❌ Error saving the session: {:?}", err);
+                            }
+                        }
                     }
-                    Err((err, state)) => {
+                    Err(err) => {
                         println!("❌ Error: {:?}", err);
-                        println!("Fallback to {}", state);
-                        state
+                        println!("Fallback to {}", game.phase());
                     }
                 };
-                state.print_instruction();
+                print_instruction(&game);
             }
             Err(ReadlineError::Interrupted) => {
                 println!("CTRL-C");
@@ -215,572 +201,276 @@ async fn main() {
     }
 }
 
-async fn cmd_setup(name: &str, client: &WebClient) -> Result<(ClientKey, usize), Error> {
-    let seed = client.get_seed().await?;
-    println!(
-        "Acquired seed for commen reference string (CRS) 0x{}",
-        hex::encode(seed)
-    );
-    println!("Setup my CRS");
-    setup(&seed);
-    println!("Generate my client key");
-    let ck = gen_client_key();
-    let user = client.register(name).await?;
-    println!("Hi {}, you are registered with ID: {}", user.name, user.id);
-    Ok((ck, user.id))
+#[cfg(feature = "keyring")]
+fn load_registration(name: &str, url: &str) -> Result<Option<UserId>, Error> {
+    SecretStore::new(name, url).load_registration()
 }
 
-async fn cmd_get_names(client: &WebClient) -> Result<(bool, Vec<String>), Error> {
-    let d = client.get_dashboard().await?;
-    d.print_presentation();
-    Ok((d.is_concluded(), d.get_names()))
+#[cfg(not(feature = "keyring"))]
+fn load_registration(_name: &str, _url: &str) -> Result<Option<UserId>, Error> {
+    Ok(None)
 }
 
-async fn cmd_submit_sks(client: &WebClient, ck: &ClientKey, user_id: &UserId) -> Result<(), Error> {
-    let total_users = 4;
-    println!("Generating server key share");
-    let sks = gen_server_key_share(*user_id, total_users, ck);
-    println!("Submit server key share");
-    client.submit_sks(*user_id, &sks).await?;
-    Ok(())
+#[cfg(feature = "keyring")]
+fn store_registration(name: &str, url: &str, user_id: UserId) -> Result<(), Error> {
+    SecretStore::new(name, url).store_registration(user_id)
 }
 
-async fn cmd_check_submit_sks_complete(client: &WebClient) -> Result<bool, Error> {
-    let d = client.get_dashboard().await?;
-    d.print_presentation();
-    Ok(d.is_submit_sks_complete())
-}
-
-async fn cmd_init_game(client: &WebClient, ck: &ClientKey, user_id: UserId) -> Result<(), Error> {
-    let initial_eggs = [false; BOARD_SIZE];
-    client.init_game(ck, user_id, &initial_eggs).await?;
+#[cfg(not(feature = "keyring"))]
+fn store_registration(_name: &str, _url: &str, _user_id: UserId) -> Result<(), Error> {
     Ok(())
 }
 
-async fn cmd_setup_game(
-    args: &[&str],
-    client: &WebClient,
-    ck: &ClientKey,
-    user_id: UserId,
-) -> Result<GameStateLocalView, Error> {
-    let x = args
-        .get(0)
-        .ok_or_else(|| anyhow!("please add init x coordinate"))?
-        .parse::<u8>()?;
-    let y = args
-        .get(1)
-        .ok_or_else(|| anyhow!("please add init y coordinate"))?
-        .parse::<u8>()?;
-
-    if x > 3 {
-        return Err(anyhow!("init x coordinate has to be in the range [0, 3]"));
-    }
-    if y > 3 {
-        return Err(anyhow!("init y coordinate has to be in the range [0, 3]"));
-    }
-
-    let view = GameStateLocalView::new(x, y, user_id);
-    client.set_starting_coords(ck, user_id, &(x, y)).await?;
-    view.print();
-    Ok(view)
+#[cfg(feature = "keyring")]
+fn load_session(name: &str, url: &str, path: &Path) -> Result<Option<Session>, Error> {
+    SecretStore::new(name, url).load_session(path)
 }
 
-async fn cmd_setup_game_complete(client: &WebClient) -> Result<bool, Error> {
-    let d = client.get_dashboard().await?;
-    d.print_presentation();
-    Ok(d.is_setup_game_complete())
+#[cfg(not(feature = "keyring"))]
+fn load_session(_name: &str, _url: &str, _path: &Path) -> Result<Option<Session>, Error> {
+    Ok(None)
 }
 
-async fn cmd_move(
-    args: &[&str],
-    client: &WebClient,
-    ck: &ClientKey,
-    user_id: UserId,
-    view: &GameStateLocalView,
-) -> Result<GameStateLocalView, Error> {
-    let arg = args
-        .get(0)
-        .ok_or_else(|| anyhow!("please add direction to move"))?;
-    let direction = match *arg {
-        "up" => Direction::Up,
-        "down" => Direction::Down,
-        "left" => Direction::Left,
-        "right" => Direction::Right,
-        &_ => bail!("invalid commmit"),
-    };
-
-    client.move_player(ck, user_id, direction).await?;
-    let mut view = view.clone();
-    view.move_player(direction);
-    view.print();
-    trigger_run(client, user_id).await?;
-    Ok(view)
+#[cfg(feature = "keyring")]
+fn store_session(name: &str, url: &str, path: &Path, session: &Session) -> Result<(), Error> {
+    SecretStore::new(name, url).store_session(path, session)
 }
 
-async fn cmd_lay(
-    client: &WebClient,
-    user_id: UserId,
-    view: &GameStateLocalView,
-) -> Result<GameStateLocalView, Error> {
-    client.lay_egg(user_id).await?;
-    let mut view = view.clone();
-    view.lay();
-    view.print();
-    trigger_run(client, user_id).await?;
-    Ok(view)
+#[cfg(not(feature = "keyring"))]
+fn store_session(_name: &str, _url: &str, _path: &Path, _session: &Session) -> Result<(), Error> {
+    Ok(())
 }
 
-async fn cmd_pickup(
-    client: &WebClient,
-    user_id: UserId,
-    view: &GameStateLocalView,
-) -> Result<GameStateLocalView, Error> {
-    client.pickup_egg(user_id).await?;
-    let mut view = view.clone();
-    view.pickup();
-    view.print();
-    trigger_run(client, user_id).await?;
-    Ok(view)
-}
+/// Every step of a round, with all 4 players and the server in this process.
+/// Server side steps are reported too, but they run on this machine's cores.
+fn bench(threads: usize) {
+    let total_users = TOTAL_USERS;
+    let mut seed = [0u8; 32];
+    thread_rng().fill_bytes(&mut seed);
+    setup(&seed);
 
-async fn trigger_run(client: &WebClient, user_id: UserId) -> Result<(), Error> {
-    println!("Requesting FHE run ...");
-    let resp = client.trigger_fhe_run(user_id).await?;
-    println!("Server: {}", resp);
-    Ok(())
-}
+    let cks = (0..total_users)
+        .map(|_| time!(gen_client_key, "[client] Generate client key"))
+        .collect_vec();
+    // On this thread, phantom-zone doesn't split it
+    let sks = cks
+        .iter()
+        .enumerate()
+        .map(|(user_id, ck)| {
+            time!(
+                || gen_server_key_share(user_id, total_users, ck),
+                "[client] Generate server key share"
+            )
+        })
+        .collect_vec();
 
-async fn cmd_fhe_complete(client: &WebClient) -> Result<bool, Error> {
-    let d = client.get_dashboard().await?;
-    Ok(d.is_fhe_complete())
+    with_thread_pool(threads, || {
+        let sks_bytes = time!(
+            || msgpack::to_compact_vec(&sks[0]).unwrap(),
+            "[client] Serialize server key share"
+        );
+        println!("Server key share size {} B", sks_bytes.len());
+        time!(|| derive_server_key(&sks), "[server] Derive server key");
+        drop(sks);
+
+        let eggs = time!(
+            || UserAction::init_game(&cks[0], &[false; BOARD_SIZE]),
+            "[client] Encrypt initial eggs"
+        );
+        let coords = cks
+            .iter()
+            .map(|ck| {
+                time!(
+                    || UserAction::set_starting_coord(ck, &(0, 0)),
+                    "[client] Encrypt starting coordinates"
+                )
+            })
+            .collect_vec();
+        let action = time!(
+            || UserAction::move_player(&cks[0], Direction::Down),
+            "[client] Encrypt move"
+        );
+
+        let state = time!(
+            || GameStateEnc {
+                coords: coords
+                    .iter()
+                    .enumerate()
+                    .map(|(user_id, coord)| coord.unpack(user_id).into_word())
+                    .collect(),
+                eggs: eggs.unpack(0).into_word().expect("init game carries eggs"),
+            },
+            "[server] Unpack setup"
+        );
+        let state = time!(
+            || evaluate_circuit(state, &[(0, action.unpack(0))]),
+            "[server] Evaluate move"
+        );
+        let output = CircuitOutput::new(time!(
+            || get_user_cell(&state, 0),
+            "[server] Evaluate get cell"
+        ));
+
+        let shares = cks
+            .iter()
+            .map(|ck| {
+                time!(
+                    || output.gen_decryption_share(ck),
+                    "[client] Generate decryption share"
+                )
+            })
+            .collect_vec();
+        let decrypted = time!(
+            || output.decrypt(&cks[0], &shares),
+            "[client] Decrypt output"
+        );
+        println!("Decrypted output: {:?}", decrypted);
+    })
 }
 
-async fn cmd_fhe_ongoing(client: &WebClient) -> Result<bool, Error> {
-    let d = client.get_dashboard().await?;
-    Ok(d.is_fhe_ongoing())
+async fn cmd_register(game: &mut GameClient, session: Option<&Path>) -> Result<(), Error> {
+    let (name, url) = (game.name().to_string(), game.client().url());
+    if let Some(path) = session {
+        if let Some(saved) = load_session(&name, &url, path)? {
+            println!(
+                "Setup my CRS and restore my client key from {}",
+                path.display()
+            );
+            game.resume(saved).await?;
+            let user_id = game.user_id().expect("resumed");
+            println!("Hi {}, resumed your game with ID: {}", name, user_id);
+            return Ok(());
+        }
+    }
+    let resume = match session {
+        Some(_) => load_registration(&name, &url)?,
+        None => None,
+    };
+    println!("Setup my CRS and generate my client key");
+    let user_id = match game.register(resume).await {
+        Err(chickens::Error::Phase(err)) if resume.is_some() => bail!(
+            "{err}: registration is closed, the stored one can't be resumed without its client key"
+        ),
+        registered => registered?,
+    };
+    if resume == Some(user_id) {
+        println!(
+            "Hi {}, resumed your registration with ID: {}",
+            name, user_id
+        );
+        return Ok(());
+    }
+    println!("Hi {}, you are registered with ID: {}", name, user_id);
+    if session.is_some() {
+        store_registration(&name, &url, user_id)?;
+    }
+    Ok(())
 }
 
-async fn cmd_download_output(
-    client: &WebClient,
-    user_id: &UserId,
-    ck: &ClientKey,
-    should_submit_shares: bool,
-    round: usize,
-) -> Result<(CircuitOutput, DecryptionSharesMap), Error> {
-    let resp = client.trigger_fhe_run(*user_id).await?;
-    if !matches!(resp, ServerState::CompletedFhe) {
-        bail!("FHE is still running")
+/// Check on what the other players or the server are doing, and move on if they are done
+async fn cmd_refresh(game: &mut GameClient) -> Result<(), Error> {
+    match game.phase() {
+        Phase::RunningFhe => game.client().get_fhe_status().await?.print_presentation(),
+        _ => game.client().get_dashboard().await?.print_presentation(),
     }
-
-    println!("Downloading fhe output");
-    let fhe_out = client.get_fhe_output().await?;
-
-    println!("Generating my decrypting shares");
-    let mut shares = HashMap::new();
-    let my_decryption_share = fhe_out.gen_decryption_share(ck);
-    shares.insert((round, *user_id), my_decryption_share.clone());
-
-    if should_submit_shares {
-        println!("Submitting my decrypting shares");
-        client
-            .submit_decryption_share(*user_id, &(round, my_decryption_share))
-            .await?;
+    if game.refresh().await? == Phase::Decrypted {
+        let output = game.output().expect("decrypted");
+        println!("Final decrypted output: {:?}", output);
+        if let Some(view) = game.view() {
+            view.print_with_output(output);
+        }
     }
-
-    Ok((fhe_out, shares))
+    Ok(())
 }
 
-async fn cmd_decryption_submission_completed(
-    client: &WebClient,
-    user_id: &UserId,
-) -> Result<bool, Error> {
-    let d = client.get_dashboard().await?;
-    d.print_presentation();
-    Ok(d.is_decryption_shares_submission_complete(*user_id))
+async fn cmd_submit_sks(game: &mut GameClient, args: &[&str]) -> Result<(), Error> {
+    println!("Generating server key share");
+    let file = args.first().map(Path::new);
+    match file {
+        Some(path) => println!("Submit server key share from {}", path.display()),
+        None => println!("Submit server key share"),
+    }
+    game.submit_key_share(file).await?;
+    Ok(())
 }
 
-async fn cmd_download_shares(
-    client: &WebClient,
-    names: &[String],
-    ck: &ClientKey,
-    shares: &mut DecryptionSharesMap,
-    co: &CircuitOutput,
-    view: &GameStateLocalView,
-    round: usize,
-) -> Result<Vec<bool>, Error> {
-    let total_users = names.len();
-    println!("Acquiring decryption shares needed");
-    for user_id in 0..total_users {
-        if shares.get(&(round, user_id)).is_none() {
-            let ds = client.get_decryption_share(round, user_id).await?;
-            shares.insert((round, user_id), ds);
-        }
-    }
-    println!("Decrypt the encrypted output");
-    let dss = (0..total_users)
-        .map(|user_id| shares.get(&(round, user_id)).expect("exists").to_owned())
-        .collect_vec();
-    let decrypted_output = co.decrypt(ck, &dss);
-    println!("Final decrypted output: {:?}", decrypted_output);
-    view.print_with_output(&decrypted_output);
-    Ok(decrypted_output)
+async fn cmd_setup_game(game: &mut GameClient, args: &[&str]) -> Result<(), Error> {
+    let x = args
+        .get(0)
+        .ok_or_else(|| anyhow!("please add init x coordinate"))?
+        .parse::<u8>()?;
+    let y = args
+        .get(1)
+        .ok_or_else(|| anyhow!("please add init y coordinate"))?
+        .parse::<u8>()?;
+
+    game.setup_game(x, y).await?;
+    game.view().expect("set up").print();
+    Ok(())
 }
 
-async fn cmd_done(client: &WebClient, user_id: UserId) -> Result<(), Error> {
-    client.done(user_id).await?;
+async fn cmd_act(game: &mut GameClient, action: Action) -> Result<(), Error> {
+    println!("Requesting FHE run ...");
+    if game.act(action).await? {
+        game.view().expect("set up").print();
+    } else {
+        println!("❌ Your action DID NOT take effect!");
+        println!("❗ Another player took an action first. Let's decrypt their output first.");
+    }
     Ok(())
 }
 
-async fn cmd_ready_for_actions(client: &WebClient, round: usize) -> Result<bool, Error> {
-    let d = client.get_dashboard().await?;
-    Ok(d.is_ready_for_actions(round))
+async fn cmd_decrypt(game: &mut GameClient) -> Result<(), Error> {
+    println!("Downloading fhe output and generating my decrypting shares");
+    game.decrypt().await?;
+    if !game.is_my_action() {
+        println!("Submitted my decrypting shares");
+    }
+    Ok(())
 }
 
-async fn run(state: State, line: &str) -> Result<State, (Error, State)> {
+async fn run(game: &mut GameClient, line: &str, session: Option<&Path>) -> Result<(), Error> {
     let terms: Vec<&str> = line.split_whitespace().collect();
     if terms.is_empty() {
-        return Ok(state);
+        return Ok(());
     }
     let cmd = &terms[0];
     let args = &terms[1..];
     if cmd == &"next" {
-        match state {
-            State::Init(s) => match cmd_setup(&s.name, &s.client).await {
-                Ok((ck, user_id)) => Ok(State::Setup(StateSetup {
-                    name: s.name,
-                    client: s.client,
-                    ck,
-                    user_id,
-                })),
-                Err(err) => Err((err, State::Init(s))),
-            },
-            State::Setup(s) => match cmd_get_names(&s.client).await {
-                Ok((is_concluded, names)) => {
-                    if is_concluded {
-                        Ok(State::ConcludedRegistration(Registration {
-                            name: s.name,
-                            client: s.client,
-                            ck: s.ck,
-                            user_id: s.user_id,
-                            names,
-                        }))
-                    } else {
-                        Ok(State::Setup(s))
-                    }
-                }
-                Err(err) => Err((err, State::Setup(s))),
-            },
-            State::ConcludedRegistration(s) => {
-                match cmd_submit_sks(&s.client, &s.ck, &s.user_id).await {
-                    Ok(()) => Ok(State::SubmittedSks(s)),
-                    Err(err) => Err((err, State::ConcludedRegistration(s))),
-                }
-            }
-            State::SubmittedSks(s) => match cmd_check_submit_sks_complete(&s.client).await {
-                Ok(is_complete) => {
-                    if is_complete {
-                        Ok(State::ConcludedSubmitSks(s))
-                    } else {
-                        Ok(State::SubmittedSks(s))
-                    }
-                }
-                Err(err) => Err((err, State::SubmittedSks(s))),
-            },
-            State::ConcludedSubmitSks(s) => {
-                match cmd_init_game(&s.client, &s.ck, s.user_id).await {
-                    Ok(()) => Ok(State::InitGame(StateGame {
-                        name: s.name,
-                        client: s.client,
-                        ck: s.ck,
-                        user_id: s.user_id,
-                        names: s.names,
-                        view: GameStateLocalView::new(0, 0, s.user_id),
-                        round: 0,
-                    })),
-                    Err(err) => Err((err, State::ConcludedSubmitSks(s))),
-                }
-            }
-            State::InitGame(s) => match cmd_setup_game(args, &s.client, &s.ck, s.user_id).await {
-                Ok(view) => Ok(State::SetupGame(StateGame { view, ..s })),
-                Err(err) => Err((err, State::InitGame(s))),
-            },
-            State::SetupGame(s) => match cmd_setup_game_complete(&s.client).await {
-                Ok(is_complete) => {
-                    if is_complete {
-                        Ok(State::ConcludedSetupGame(s))
-                    } else {
-                        Ok(State::SetupGame(s))
-                    }
-                }
-                Err(err) => Err((err, State::SetupGame(s))),
-            },
-            State::ConcludedSetupGame(s) => Ok(State::ConcludedSetupGame(s)),
-            State::GameAction(s) => match cmd_fhe_complete(&s.client).await {
-                Ok(is_complete) => {
-                    if is_complete {
-                        Ok(State::CompletedFhe(s))
-                    } else {
-                        Ok(State::GameAction(s))
-                    }
-                }
-                Err(err) => Err((err, State::GameAction(s))),
-            },
-            State::CompletedFhe(s) => {
-                match cmd_download_output(&s.client, &s.user_id, &s.ck, !s.is_my_action, s.round)
-                    .await
-                {
-                    Ok((fhe_out, shares)) => Ok(State::DownloadedOutput(StateDownloadedOutput {
-                        name: s.name,
-                        client: s.client,
-                        ck: s.ck,
-                        user_id: s.user_id,
-                        names: s.names,
-                        fhe_out,
-                        shares,
-                        view: s.view,
-                        is_my_action: s.is_my_action,
-                        round: s.round,
-                    })),
-                    Err(err) => Err((err, State::CompletedFhe(s))),
-                }
-            }
-            State::DownloadedOutput(s) => {
-                if !s.is_my_action {
-                    return Ok(State::Decrypted(StateDecrypted {
-                        name: s.name,
-                        client: s.client,
-                        ck: s.ck,
-                        user_id: s.user_id,
-                        names: s.names,
-                        view: s.view,
-                        decrypted_output: None,
-                        is_my_action: false,
-                        round: s.round,
-                    }));
-                }
-                match cmd_decryption_submission_completed(&s.client, &s.user_id).await {
-                    Ok(is_complete) => {
-                        if is_complete {
-                            Ok(State::ConcludedDecryptionSubmission(s))
-                        } else {
-                            Ok(State::DownloadedOutput(s))
-                        }
-                    }
-                    Err(err) => Err((err, State::DownloadedOutput(s))),
-                }
-            }
-
-            State::ConcludedDecryptionSubmission(mut s) => {
-                match cmd_download_shares(
-                    &s.client,
-                    &s.names,
-                    &s.ck,
-                    &mut s.shares,
-                    &s.fhe_out,
-                    &s.view,
-                    s.round,
-                )
-                .await
-                {
-                    Ok(decrypted_output) => Ok(State::Decrypted(StateDecrypted {
-                        name: s.name,
-                        client: s.client,
-                        ck: s.ck,
-                        user_id: s.user_id,
-                        names: s.names,
-                        decrypted_output: Some(decrypted_output),
-                        view: s.view,
-                        is_my_action: true,
-                        round: s.round,
-                    })),
-                    Err(err) => Err((err, State::DownloadedOutput(s))),
-                }
-            }
-            State::Decrypted(s) => match cmd_done(&s.client, s.user_id).await {
-                Ok(()) => Ok(State::NewRound(StateGame {
-                    name: s.name,
-                    client: s.client,
-                    ck: s.ck,
-                    user_id: s.user_id,
-                    names: s.names,
-                    view: s.view,
-                    round: s.round,
-                })),
-                Err(err) => Err((err, State::Decrypted(s))),
-            },
-            State::NewRound(s) => match cmd_ready_for_actions(&s.client, s.round).await {
-                Ok(is_ready) => {
-                    if is_ready {
-                        Ok(State::ConcludedSetupGame(StateGame {
-                            round: s.round + 1,
-                            ..s
-                        }))
-                    } else {
-                        Ok(State::NewRound(s))
-                    }
-                }
-                Err(err) => Err((err, State::NewRound(s))),
-            },
+        match game.phase() {
+            Phase::Unregistered => cmd_register(game, session).await,
+            Phase::RegistrationClosed => cmd_submit_sks(game, args).await,
+            Phase::ReadyForSetup => cmd_setup_game(game, args).await,
+            Phase::CompletedFhe => cmd_decrypt(game).await,
+            Phase::Decrypted => Ok(game.finish_round().await?),
+            _ => cmd_refresh(game).await,
         }
     } else if cmd == &"move" {
-        match state {
-            State::ConcludedSetupGame(s) => {
-                match cmd_move(args, &s.client, &s.ck, s.user_id, &s.view).await {
-                    Ok(view) => Ok(State::GameAction(StateGameAction {
-                        view,
-                        is_my_action: true,
-                        name: s.name,
-                        client: s.client,
-                        ck: s.ck,
-                        user_id: s.user_id,
-                        names: s.names,
-                        round: s.round,
-                    })),
-                    Err(err) => {
-                        if err.to_string().contains("Wrong server state") {
-                            let result = match cmd_fhe_ongoing(&s.client).await {
-                                Ok(is_ongoing) => {
-                                    if is_ongoing {
-                                        println!("❌ Your action DID NOT take effect!");
-                                        println!("❗ Another player took an action first. Let's decrypt their output first.");
-                                        Ok(State::GameAction(StateGameAction {
-                                            is_my_action: false,
-                                            name: s.name,
-                                            client: s.client,
-                                            ck: s.ck,
-                                            user_id: s.user_id,
-                                            names: s.names,
-                                            view: s.view,
-                                            round: s.round,
-                                        }))
-                                    } else {
-                                        Err((err, State::ConcludedSetupGame(s)))
-                                    }
-                                }
-                                Err(err) => Err((err, State::ConcludedSetupGame(s))),
-                            };
-                            return result;
-                        }
-                        Err((err, State::ConcludedSetupGame(s)))
-                    }
-                }
-            }
-            _ => Err((anyhow!("Invalid state for command {}", cmd), state)),
-        }
+        let arg = args
+            .get(0)
+            .ok_or_else(|| anyhow!("please add direction to move"))?;
+        let direction = match *arg {
+            "up" => Direction::Up,
+            "down" => Direction::Down,
+            "left" => Direction::Left,
+            "right" => Direction::Right,
+            &_ => bail!("invalid commmit"),
+        };
+        cmd_act(game, Action::Move(direction)).await
     } else if cmd == &"lay" {
-        match state {
-            State::ConcludedSetupGame(s) => match cmd_lay(&s.client, s.user_id, &s.view).await {
-                Ok(view) => Ok(State::GameAction(StateGameAction {
-                    view,
-                    is_my_action: true,
-                    name: s.name,
-                    client: s.client,
-                    ck: s.ck,
-                    user_id: s.user_id,
-                    names: s.names,
-                    round: s.round,
-                })),
-                Err(err) => {
-                    if err.to_string().contains("Wrong server state") {
-                        let result = match cmd_fhe_ongoing(&s.client).await {
-                            Ok(is_ongoing) => {
-                                if is_ongoing {
-                                    println!("❌ Your action DID NOT take effect!");
-                                    println!("❗ Another player took an action first. Let's decrypt their output first.");
-                                    Ok(State::GameAction(StateGameAction {
-                                        is_my_action: false,
-                                        name: s.name,
-                                        client: s.client,
-                                        ck: s.ck,
-                                        user_id: s.user_id,
-                                        names: s.names,
-                                        view: s.view,
-                                        round: s.round,
-                                    }))
-                                } else {
-                                    Err((err, State::ConcludedSetupGame(s)))
-                                }
-                            }
-                            Err(err) => Err((err, State::ConcludedSetupGame(s))),
-                        };
-                        return result;
-                    }
-                    Err((err, State::ConcludedSetupGame(s)))
-                }
-            },
-            _ => Err((anyhow!("Invalid state for command {}", cmd), state)),
-        }
+        cmd_act(game, Action::LayEgg).await
     } else if cmd == &"pickup" {
-        match state {
-            State::ConcludedSetupGame(s) => match cmd_pickup(&s.client, s.user_id, &s.view).await {
-                Ok(view) => Ok(State::GameAction(StateGameAction {
-                    view,
-                    is_my_action: true,
-                    name: s.name,
-                    client: s.client,
-                    ck: s.ck,
-                    user_id: s.user_id,
-                    names: s.names,
-                    round: s.round,
-                })),
-                Err(err) => {
-                    if err.to_string().contains("Wrong server state") {
-                        let result = match cmd_fhe_ongoing(&s.client).await {
-                            Ok(is_ongoing) => {
-                                if is_ongoing {
-                                    println!("❌ Your action DID NOT take effect!");
-                                    println!("❗ Another player took an action first. Let's decrypt their output first.");
-                                    Ok(State::GameAction(StateGameAction {
-                                        is_my_action: false,
-                                        name: s.name,
-                                        client: s.client,
-                                        ck: s.ck,
-                                        user_id: s.user_id,
-                                        names: s.names,
-                                        view: s.view,
-                                        round: s.round,
-                                    }))
-                                } else {
-                                    Err((err, State::ConcludedSetupGame(s)))
-                                }
-                            }
-                            Err(err) => Err((err, State::ConcludedSetupGame(s))),
-                        };
-                        return result;
-                    }
-                    Err((err, State::ConcludedSetupGame(s)))
-                }
-            },
-            _ => Err((anyhow!("Invalid state for command {}", cmd), state)),
-        }
+        cmd_act(game, Action::PickupEgg).await
     } else if cmd == &"status" {
-        match &state {
-            State::Init(StateInit { client, .. })
-            | State::Setup(StateSetup { client, .. })
-            | State::ConcludedRegistration(Registration { client, .. })
-            | State::SubmittedSks(Registration { client, .. })
-            | State::ConcludedSubmitSks(Registration { client, .. })
-            | State::InitGame(StateGame { client, .. })
-            | State::SetupGame(StateGame { client, .. })
-            | State::ConcludedSetupGame(StateGame { client, .. })
-            | State::GameAction(StateGameAction { client, .. })
-            | State::CompletedFhe(StateGameAction { client, .. })
-            | State::DownloadedOutput(StateDownloadedOutput { client, .. })
-            | State::ConcludedDecryptionSubmission(StateDownloadedOutput { client, .. })
-            | State::NewRound(StateGame { client, .. })
-            | State::Decrypted(StateDecrypted { client, .. }) => {
-                match client.get_dashboard().await {
-                    Ok(dashbaord) => {
-                        dashbaord.print_presentation();
-                        Ok(state)
-                    }
-                    Err(err) => Err((err, state)),
-                }
-            }
+        let dashboard = game.client().get_dashboard().await?;
+        match args.first() {
+            Some(&"json") => println!("{}", dashboard.to_json()),
+            _ => dashboard.print_presentation(),
         }
+        Ok(())
     } else if cmd.starts_with('#') {
-        Ok(state)
+        Ok(())
     } else {
-        Err((anyhow!("Unknown command {}", cmd), state))
+        Err(anyhow!("Unknown command {}", cmd))
     }
 }
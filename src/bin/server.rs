@@ -1,7 +1,18 @@
-use chickens::rocket;
+use chickens::{init_tracing, rocket, LogFormat};
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    /// Format of the logs, json has an object a line
+    #[arg(long, value_enum, default_value_t)]
+    log_format: LogFormat,
+}
 
 #[rocket::main]
 async fn main() -> Result<(), rocket::Error> {
+    let cli = Cli::parse();
+    init_tracing(cli.log_format);
     rocket().launch().await?;
     Ok(())
 }
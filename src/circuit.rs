@@ -1,32 +1,68 @@
 use crate::{
     compiled::{get_cell, lay_egg, move_player, pickup_egg},
-    time,
-    types::{GameStateEnc, ServerKeyShare, Word},
+    fhe::{self, aggregate_server_key_shares, set_parameter_set, ParameterSelector},
+    types::{ClientKey, GameStateEnc, ServerKeyShare, Word},
     UserAction, UserId,
 };
 use itertools::Itertools;
-use phantom_zone::{aggregate_server_key_shares, set_parameter_set, ParameterSelector};
+use tracing::{info_span, instrument};
 
 pub const PARAMETER: ParameterSelector = ParameterSelector::NonInteractiveLTE4Party;
 
+/// Run `op` on a scoped rayon pool of `num_threads` threads, 0 picks rayon's default.
+/// phantom-zone keeps the parameter set thread-local, so every worker sets it first.
+pub fn with_thread_pool<R: Send>(num_threads: usize, op: impl FnOnce() -> R + Send) -> R {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build_scoped(
+            |thread| {
+                set_parameter_set(PARAMETER);
+                thread.run()
+            },
+            |pool| {
+                set_parameter_set(PARAMETER);
+                pool.install(op)
+            },
+        )
+        .unwrap()
+}
+
+/// A rayon pool of `num_threads` threads with the parameter set, 0 picks rayon's default.
+/// For callers running many short jobs, where [`with_thread_pool`] would build one per job.
+pub fn thread_pool(num_threads: usize) -> rayon::ThreadPool {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .start_handler(|_| set_parameter_set(PARAMETER))
+        .build()
+        .unwrap()
+}
+
+/// Client work, long running. phantom-zone generates the share on the calling thread and
+/// this crate can't split it, so unlike decryption shares it doesn't take a pool.
+pub fn gen_server_key_share(
+    user_id: UserId,
+    total_users: usize,
+    client_key: &ClientKey,
+) -> ServerKeyShare {
+    set_parameter_set(PARAMETER);
+    fhe::gen_server_key_share(user_id, total_users, client_key)
+}
+
 /// Server work
 /// Warning: global variable change
-pub(crate) fn derive_server_key(server_key_shares: &[ServerKeyShare]) {
-    let server_key = time!(
-        || aggregate_server_key_shares(server_key_shares),
-        "Aggregate server key shares"
-    );
+#[instrument(skip_all)]
+pub fn derive_server_key(server_key_shares: &[ServerKeyShare]) {
+    let server_key = info_span!("aggregate_server_key_shares")
+        .in_scope(|| aggregate_server_key_shares(server_key_shares));
     server_key.set_server_key();
 }
 
-pub(crate) fn evaluate_circuit(
-    state: GameStateEnc,
-    uas: &[(UserId, UserAction<Word>)],
-) -> GameStateEnc {
+#[instrument(skip_all, fields(actions = uas.len()))]
+pub fn evaluate_circuit(state: GameStateEnc, uas: &[(UserId, UserAction<Word>)]) -> GameStateEnc {
     let mut state = state.clone();
     for (user_id, ua) in uas {
-        println!("Apply action {} for user {}", ua, user_id);
-        state = apply_action(state, *user_id, ua);
+        let span = info_span!("apply_action", user_id, action = %ua);
+        state = span.in_scope(|| apply_action(state, *user_id, ua));
     }
     state
 }
@@ -52,18 +88,17 @@ pub(crate) fn apply_action(
             next_state.eggs =
                 pickup_egg(&state.coords[user_id].as_ref().expect("exist"), &state.eggs);
         }
-        UserAction::InitGame { .. }
-        | UserAction::SetStartingCoord { .. }
-        | UserAction::GetCell { .. }
-        | UserAction::Done => {
+        // Only looks at the cell, which is read after every run anyway
+        UserAction::GetCell => {}
+        UserAction::InitGame { .. } | UserAction::SetStartingCoord { .. } | UserAction::Done => {
             unreachable!("Shouldn't be in the action queue")
         }
     };
     next_state
 }
 
-pub(crate) fn get_user_cell(state: &GameStateEnc, user_id: UserId) -> Word {
-    println!("Get cell for user {}", user_id);
+#[instrument(skip(state))]
+pub fn get_user_cell(state: &GameStateEnc, user_id: UserId) -> Word {
     set_parameter_set(PARAMETER);
     let coords = state.coords.iter().flatten().cloned().collect_vec();
     get_cell(
@@ -1,21 +1,37 @@
+#[cfg(feature = "grpc")]
+use crate::grpc::GrpcClient;
 use crate::{
-    dashboard::{Dashboard, RegisteredUser},
+    dashboard::{Dashboard, FheStatus, RegisteredUser},
+    error::{Error, ErrorBody, ProtocolError},
+    replay::{Recorder, Replay},
+    telemetry::{new_request_id, REQUEST_ID_HEADER},
     types::{
         AnnotatedDecryptionShare, CircuitOutput, DecryptionShare, DecryptionShareSubmission,
-        EncryptedWord, Seed, ServerKeyShare, ServerState, SksSubmission, UserAction, UserId,
+        EncryptedWord, Param, Seed, ServerKeyShare, ServerState, SksSubmission, UserAction, UserId,
     },
     ClientKey, Direction,
 };
-use anyhow::{anyhow, bail, Error};
+use futures::stream::{self, LocalBoxStream, StreamExt};
 use indicatif::{ProgressBar, ProgressStyle};
-use reqwest::{self, header::CONTENT_TYPE, Client};
-use rocket::serde::msgpack;
+use reqwest::{
+    self,
+    header::{ACCEPT, CONTENT_TYPE},
+    Client,
+};
+use rocket::{
+    http::{Accept, ContentType, Header, MediaType, Method},
+    serde::msgpack,
+};
 use serde::{Deserialize, Serialize};
 use std::{
+    fs,
+    io::{BufWriter, Cursor, Write},
+    path::Path,
     pin::Pin,
     task::{Context, Poll},
+    time::Duration,
 };
-use tokio::io::AsyncRead;
+use tokio::{fs::File, io::AsyncRead, time::sleep};
 use tokio_util::io::ReaderStream;
 
 pub enum WebClient {
@@ -24,106 +40,284 @@ pub enum WebClient {
         client: reqwest::Client,
     },
     Test(Box<rocket::local::asynchronous::Client>),
+    /// Forwards to `inner` and records every exchange to a fixture
+    Record {
+        inner: Box<WebClient>,
+        recorder: Recorder,
+    },
+    /// Serves the exchanges of a fixture back, no server needed
+    Replay(Replay),
+    /// Speaks gRPC instead of HTTP, for `grpc://` urls
+    #[cfg(feature = "grpc")]
+    Grpc(GrpcClient),
+}
+
+/// Body of a request, encoded before it gets to the transport
+pub(crate) enum RequestBody<'a> {
+    Empty,
+    Bytes(Vec<u8>),
+    MsgPack(Vec<u8>),
+    /// Msgpack serialized to a file before, streamed from there
+    MsgPackFile(&'a Path),
+}
+
+/// Response before it is decoded. Routes answer json unless msgpack is asked for,
+/// errors are always json
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct RawResponse {
+    pub(crate) status: u16,
+    pub(crate) body: ResponseBody,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub(crate) enum ResponseBody {
+    Json(String),
+    /// Hex encoded in fixtures
+    MsgPack(#[serde(with = "hex")] Vec<u8>),
+}
+
+impl ResponseBody {
+    fn new(content_type: Option<&ContentType>, bytes: Vec<u8>) -> Self {
+        match content_type {
+            Some(content_type) if content_type.is_msgpack() => Self::MsgPack(bytes),
+            // Not json if it isn't utf-8, decoding it tells
+            _ => Self::Json(String::from_utf8_lossy(&bytes).into_owned()),
+        }
+    }
+}
+
+impl RawResponse {
+    /// The answer, or the error the server answered
+    pub(crate) fn decode<T: for<'de> Deserialize<'de>>(self) -> Result<T, Error> {
+        let status = self.status;
+        let unexpected = |reason: String| ProtocolError::UnexpectedResponse { status, reason };
+        match (status, self.body) {
+            (200, ResponseBody::Json(body)) => {
+                serde_json::from_str(&body).map_err(|err| unexpected(err.to_string()).into())
+            }
+            (200, ResponseBody::MsgPack(body)) => {
+                msgpack::from_slice(&body).map_err(|err| unexpected(err.to_string()).into())
+            }
+            (_, ResponseBody::Json(body)) => match serde_json::from_str::<ErrorBody>(&body) {
+                Ok(body) => Err(body.error),
+                Err(_) => Err(unexpected(body).into()),
+            },
+            (_, body) => Err(unexpected(format!("{:?}", body)).into()),
+        }
+    }
 }
 
 impl WebClient {
+    /// Client of the server at `url`. With the `grpc` feature, `grpc://` urls go to the
+    /// server's gRPC port.
     pub fn new(url: &str) -> Self {
+        #[cfg(feature = "grpc")]
+        if url.starts_with("grpc://") {
+            return Self::Grpc(GrpcClient::new(url));
+        }
         Self::Prod {
             url: url.to_string(),
             client: Client::new(),
         }
     }
 
+    /// Record every exchange of `inner` to `fixture`, to [`WebClient::replay`] later
+    pub fn record(inner: WebClient, fixture: &Path) -> Result<Self, Error> {
+        Ok(Self::Record {
+            inner: Box::new(inner),
+            recorder: Recorder::create(fixture)?,
+        })
+    }
+
+    /// Serve the exchanges recorded in `fixture`, in order
+    pub fn replay(fixture: &Path) -> Result<Self, Error> {
+        Ok(Self::Replay(Replay::load(fixture)?))
+    }
+
     pub fn url(&self) -> String {
         match self {
             WebClient::Prod { url, .. } => url.to_string(),
             WebClient::Test(_) => panic!("No url for testing"),
+            WebClient::Record { inner, .. } => inner.url(),
+            WebClient::Replay(replay) => replay.fixture(),
+            #[cfg(feature = "grpc")]
+            WebClient::Grpc(client) => client.url(),
         }
     }
 
     fn path(&self, path: &str) -> String {
         match self {
             WebClient::Prod { url, .. } => format!("{}/{}", url, path),
-            WebClient::Test(_) => unreachable!(),
+            _ => unreachable!(),
         }
     }
 
-    async fn get<T: Send + for<'de> Deserialize<'de> + 'static>(
+    pub(crate) async fn send(
         &self,
+        method: Method,
         path: &str,
-    ) -> Result<T, Error> {
+        accept: &MediaType,
+        body: RequestBody<'_>,
+    ) -> Result<RawResponse, Error> {
         match self {
             WebClient::Prod { client, .. } => {
-                let response = client.get(self.path(path)).send().await?;
-                handle_response_prod(response).await
+                let request = match method {
+                    Method::Get => client.get(self.path(path)),
+                    _ => client.post(self.path(path)),
+                };
+                let request = request
+                    .header(ACCEPT, accept.to_string())
+                    .header(REQUEST_ID_HEADER, new_request_id());
+                let request = match body {
+                    RequestBody::Empty => request,
+                    RequestBody::Bytes(bytes) => request.body(bytes),
+                    RequestBody::MsgPack(bytes) => {
+                        let total_bytes = bytes.len() as u64;
+                        // The reader takes the serialized body over, no copy is made
+                        request
+                            .header(CONTENT_TYPE, "application/msgpack")
+                            .body(upload_body(Cursor::new(bytes), total_bytes))
+                    }
+                    RequestBody::MsgPackFile(file) => {
+                        let file = File::open(file).await?;
+                        let total_bytes = file.metadata().await?.len();
+                        request
+                            .header(CONTENT_TYPE, "application/msgpack")
+                            .body(upload_body(file, total_bytes))
+                    }
+                };
+                let response = request.send().await?;
+                let status = response.status().as_u16();
+                let content_type = response
+                    .headers()
+                    .get(CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(ContentType::parse_flexible);
+                let body = response.bytes().await?.to_vec();
+                Ok(RawResponse {
+                    status,
+                    body: ResponseBody::new(content_type.as_ref(), body),
+                })
             }
             WebClient::Test(client) => {
-                let response = client.get(path).dispatch().await;
-                handle_response_test(response).await
+                let request = match method {
+                    Method::Get => client.get(path),
+                    _ => client.post(path),
+                };
+                let request = request
+                    .header(Accept::from(accept.clone()))
+                    .header(Header::new(REQUEST_ID_HEADER, new_request_id()));
+                let request = match body {
+                    RequestBody::Empty => request,
+                    RequestBody::Bytes(bytes) => request.body(bytes),
+                    RequestBody::MsgPack(bytes) => request.header(ContentType::MsgPack).body(bytes),
+                    RequestBody::MsgPackFile(file) => request
+                        .header(ContentType::MsgPack)
+                        .body(tokio::fs::read(file).await?),
+                };
+                let response = request.dispatch().await;
+                let status = response.status().code;
+                let content_type = response.content_type();
+                let body = response.into_bytes().await.ok_or_else(|| {
+                    let reason = "Can't read response body".to_string();
+                    ProtocolError::UnexpectedResponse { status, reason }
+                })?;
+                Ok(RawResponse {
+                    status,
+                    body: ResponseBody::new(content_type.as_ref(), body),
+                })
+            }
+            WebClient::Record { inner, recorder } => {
+                let response = Box::pin(inner.send(method, path, accept, body)).await?;
+                recorder.record(method, path, &response)?;
+                Ok(response)
             }
+            WebClient::Replay(replay) => replay.next(method, path),
+            #[cfg(feature = "grpc")]
+            WebClient::Grpc(client) => client.send(method, path, body).await,
         }
     }
+
+    async fn get<T: Send + for<'de> Deserialize<'de> + 'static>(
+        &self,
+        path: &str,
+    ) -> Result<T, Error> {
+        self.send(Method::Get, path, &MediaType::JSON, RequestBody::Empty)
+            .await?
+            .decode()
+    }
+    /// For bulk responses, msgpack is a fraction of the size of json
+    async fn get_msgpack<T: Send + for<'de> Deserialize<'de> + 'static>(
+        &self,
+        path: &str,
+    ) -> Result<T, Error> {
+        self.send(Method::Get, path, &MediaType::MsgPack, RequestBody::Empty)
+            .await?
+            .decode()
+    }
     async fn post_nobody<T: Send + for<'de> Deserialize<'de> + 'static>(
         &self,
         path: &str,
     ) -> Result<T, Error> {
-        match self {
-            WebClient::Prod { client, .. } => {
-                let response = client.post(self.path(path)).send().await?;
-                handle_response_prod(response).await
-            }
-            WebClient::Test(client) => {
-                let response = client.post(path).dispatch().await;
-                handle_response_test(response).await
-            }
-        }
+        self.send(Method::Post, path, &MediaType::JSON, RequestBody::Empty)
+            .await?
+            .decode()
     }
     async fn post<T: Send + for<'de> Deserialize<'de> + 'static>(
         &self,
         path: &str,
         body: Vec<u8>,
     ) -> Result<T, Error> {
-        match self {
-            WebClient::Prod { client, .. } => {
-                let response = client.post(self.path(path)).body(body).send().await?;
-                handle_response_prod(response).await
-            }
-            WebClient::Test(client) => {
-                let response = client.post(path).body(body).dispatch().await;
-                handle_response_test(response).await
-            }
-        }
+        self.send(
+            Method::Post,
+            path,
+            &MediaType::JSON,
+            RequestBody::Bytes(body),
+        )
+        .await?
+        .decode()
     }
     async fn post_msgpack<T: Send + for<'de> Deserialize<'de> + 'static>(
         &self,
         path: &str,
         body: &impl Serialize,
     ) -> Result<T, Error> {
-        match self {
-            WebClient::Prod { client, .. } => {
-                let body = msgpack::to_compact_vec(body)?;
-                let reader = ProgressReader::new(&body, 128 * 1024);
-                let stream = ReaderStream::new(reader);
-
-                let response = client
-                    .post(self.path(path))
-                    .header(CONTENT_TYPE, "application/msgpack")
-                    .body(reqwest::Body::wrap_stream(stream))
-                    .send()
-                    .await?;
-                handle_response_prod(response).await
-            }
-            WebClient::Test(client) => {
-                let response = client.post(path).msgpack(body).dispatch().await;
-                handle_response_test(response).await
-            }
-        }
+        let body = msgpack::to_compact_vec(body)?;
+        self.send(
+            Method::Post,
+            path,
+            &MediaType::JSON,
+            RequestBody::MsgPack(body),
+        )
+        .await?
+        .decode()
+    }
+    /// Post a body serialized to `file` before, without loading it in memory
+    async fn post_msgpack_file<T: Send + for<'de> Deserialize<'de> + 'static>(
+        &self,
+        path: &str,
+        file: &Path,
+    ) -> Result<T, Error> {
+        self.send(
+            Method::Post,
+            path,
+            &MediaType::JSON,
+            RequestBody::MsgPackFile(file),
+        )
+        .await?
+        .decode()
     }
 
-    pub async fn get_seed(&self) -> Result<Seed, Error> {
+    pub async fn get_param(&self) -> Result<Param, Error> {
         self.get("/param").await
     }
 
+    pub async fn get_seed(&self) -> Result<Seed, Error> {
+        Ok(self.get_param().await?.seed)
+    }
+
     pub async fn register(&self, name: &str) -> Result<RegisteredUser, Error> {
         self.post("/register", name.as_bytes().to_vec()).await
     }
@@ -133,13 +327,16 @@ impl WebClient {
     }
 
     pub async fn submit_sks(&self, user_id: UserId, sks: &ServerKeyShare) -> Result<UserId, Error> {
-        let submission = SksSubmission {
-            user_id,
-            sks: sks.clone(),
-        };
+        let submission = SksSubmission::new(user_id, sks);
         self.post_msgpack("/submit_sks", &submission).await
     }
 
+    /// Upload a key share saved with [`write_sks_submission`]. The file is streamed,
+    /// so the share never has to fit in memory.
+    pub async fn submit_sks_from_file(&self, path: &Path) -> Result<UserId, Error> {
+        self.post_msgpack_file("/submit_sks", path).await
+    }
+
     async fn setup_game(
         &self,
         user_id: UserId,
@@ -210,8 +407,31 @@ impl WebClient {
         self.post_nobody(&format!("/run/{user_id}")).await
     }
 
+    pub async fn get_fhe_status(&self) -> Result<FheStatus, Error> {
+        self.get("/fhe_status").await
+    }
+
+    /// Status of the ongoing run, then on every change until the run is over. The server
+    /// pushes the changes over gRPC, other transports poll for them.
+    pub async fn watch_fhe(&self) -> Result<LocalBoxStream<'_, Result<FheStatus, Error>>, Error> {
+        #[cfg(feature = "grpc")]
+        if let WebClient::Grpc(client) = self {
+            return Ok(client.watch_fhe().await?.boxed_local());
+        }
+        let polls = stream::unfold(Some(true), move |poll| async move {
+            let first = poll?;
+            if !first {
+                sleep(Duration::from_millis(500)).await;
+            }
+            let status = self.get_fhe_status().await;
+            let running = matches!(&status, Ok(status) if status.status == ServerState::RunningFhe);
+            Some((status, running.then_some(false)))
+        });
+        Ok(polls.boxed_local())
+    }
+
     pub async fn get_fhe_output(&self) -> Result<CircuitOutput, Error> {
-        self.get("/fhe_output").await
+        self.get_msgpack("/fhe_output").await
     }
 
     pub async fn submit_decryption_share(
@@ -219,10 +439,7 @@ impl WebClient {
         user_id: usize,
         decryption_share: &AnnotatedDecryptionShare,
     ) -> Result<UserId, Error> {
-        let submission = DecryptionShareSubmission {
-            user_id,
-            decryption_share: decryption_share.clone(),
-        };
+        let submission = DecryptionShareSubmission::new(user_id, decryption_share.clone());
         self.post_msgpack("/submit_decryption_share", &submission)
             .await
     }
@@ -232,51 +449,64 @@ impl WebClient {
         output_id: usize,
         user_id: usize,
     ) -> Result<DecryptionShare, Error> {
-        self.get(&format!("/decryption_share/{output_id}/{user_id}"))
+        self.get_msgpack(&format!("/decryption_share/{output_id}/{user_id}"))
             .await
     }
 }
 
-async fn handle_response_prod<T: Send + for<'de> Deserialize<'de> + 'static>(
-    response: reqwest::Response,
-) -> Result<T, Error> {
-    match response.status().as_u16() {
-        200 => Ok(response.json::<T>().await?),
-        _ => {
-            let err = response.text().await?;
-            bail!("Server responded error: {:?}", err)
-        }
-    }
+/// Streams `reader` as a request body, with an upload progress bar
+fn upload_body(
+    reader: impl AsyncRead + Send + Sync + Unpin + 'static,
+    total_bytes: u64,
+) -> reqwest::Body {
+    reqwest::Body::wrap_stream(upload_stream(reader, total_bytes))
 }
 
-async fn handle_response_test<T: Send + for<'de> Deserialize<'de> + 'static>(
-    response: rocket::local::asynchronous::LocalResponse<'_>,
-) -> Result<T, Error> {
-    match response.status().code {
-        200 => response
-            .into_json::<T>()
-            .await
-            .ok_or(anyhow!("Can't parse response output")),
-        _ => {
-            let err = response
-                .into_string()
-                .await
-                .ok_or(anyhow!("Can't parse response output"))?;
-            bail!("Server responded error: {:?}", err)
-        }
-    }
+/// Chunks of `reader` to upload, with an upload progress bar
+pub(crate) fn upload_stream<R: AsyncRead + Unpin>(
+    reader: R,
+    total_bytes: u64,
+) -> ReaderStream<ProgressReader<R>> {
+    ReaderStream::with_capacity(ProgressReader::new(reader, total_bytes), 128 * 1024)
+}
+
+/// Body of a `/submit_sks` request, for clients that bring their own transport
+pub fn sks_submission_body(user_id: UserId, sks: &ServerKeyShare) -> Result<Vec<u8>, Error> {
+    Ok(msgpack::to_compact_vec(&SksSubmission::new(user_id, sks))?)
+}
+
+/// Body of a `/submit_decryption_share` request, for clients that bring their own transport
+pub fn decryption_share_body(
+    user_id: UserId,
+    output_id: usize,
+    share: DecryptionShare,
+) -> Result<Vec<u8>, Error> {
+    let submission = DecryptionShareSubmission::new(user_id, (output_id, share));
+    Ok(msgpack::to_compact_vec(&submission)?)
 }
 
-struct ProgressReader {
-    inner: Vec<u8>,
+/// Serialize a key share submission to `path`, the share can be dropped afterwards
+/// and uploaded later with [`WebClient::submit_sks_from_file`].
+pub fn write_sks_submission(
+    path: &Path,
+    user_id: UserId,
+    sks: &ServerKeyShare,
+) -> Result<(), Error> {
+    let mut writer = BufWriter::new(fs::File::create(path)?);
+    rmp_serde::encode::write(&mut writer, &SksSubmission::new(user_id, sks))?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reports on a progress bar how much of the wrapped reader has been uploaded
+pub(crate) struct ProgressReader<R> {
+    inner: R,
     progress_bar: ProgressBar,
-    position: usize,
-    chunk_size: usize,
+    position: u64,
 }
 
-impl ProgressReader {
-    fn new(body: &[u8], chunk_size: usize) -> Self {
-        let total_bytes = body.len() as u64;
+impl<R: AsyncRead + Unpin> ProgressReader<R> {
+    fn new(inner: R, total_bytes: u64) -> Self {
         println!("Total size {} B", total_bytes);
         let bar = ProgressBar::new(total_bytes);
         bar.set_style(
@@ -289,31 +519,33 @@ impl ProgressReader {
         bar.set_message("Uploading...");
 
         Self {
-            inner: body.to_vec(),
+            inner,
             progress_bar: bar,
             position: 0,
-            chunk_size,
         }
     }
 }
 
-impl AsyncRead for ProgressReader {
+impl<R: AsyncRead + Unpin> AsyncRead for ProgressReader<R> {
     fn poll_read(
-        mut self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> Poll<tokio::io::Result<()>> {
-        let remaining = self.inner.len() - self.position;
-        let to_read = self.chunk_size.min(remaining.min(buf.remaining()));
-        let end = self.position + to_read;
-        buf.put_slice(&self.inner[self.position..end]);
-        self.position = end;
-        self.progress_bar.set_position(self.position as u64);
-
-        if to_read == 0 {
-            self.progress_bar.finish_with_message("Upload complete")
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+
+        if let Poll::Ready(Ok(())) = poll {
+            let read = buf.filled().len() - filled;
+            this.position += read as u64;
+            this.progress_bar.set_position(this.position);
+
+            if read == 0 {
+                this.progress_bar.finish_with_message("Upload complete")
+            }
         }
 
-        Poll::Ready(Ok(()))
+        poll
     }
 }
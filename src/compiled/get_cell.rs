@@ -2,7 +2,7 @@
 use rayon::prelude::*;
 use std::collections::HashMap;
 
-use phantom_zone::*;
+use crate::fhe::*;
 
 type Ciphertext = FheBool;
 
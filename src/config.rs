@@ -0,0 +1,178 @@
+//! Parameters of a game, defined once. The server is started with them and answers them
+//! in `/param` next to the seed, clients encode their actions after them.
+
+use crate::error::{Error, ProtocolError};
+use crate::types::{BOARD_DIM, TOTAL_USERS};
+use rocket::serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+/// Where a player moving off the board ends up
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum EdgeBehavior {
+    /// On the opposite edge
+    #[default]
+    Wrap,
+}
+
+/// Only some of it is up to the config: the circuits are compiled for [`TOTAL_USERS`]
+/// players on a [`BOARD_DIM`] board, [`GameConfigBuilder::build`] turns down anything else.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct GameConfig {
+    players: usize,
+    board_dim: usize,
+    round_timer_secs: Option<u64>,
+    edge: EdgeBehavior,
+    max_rounds: Option<usize>,
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        Self {
+            players: TOTAL_USERS,
+            board_dim: BOARD_DIM,
+            round_timer_secs: None,
+            edge: EdgeBehavior::Wrap,
+            max_rounds: None,
+        }
+    }
+}
+
+impl GameConfig {
+    pub fn builder() -> GameConfigBuilder {
+        GameConfigBuilder::default()
+    }
+
+    /// Players the game takes, registration closes once they joined
+    pub fn players(&self) -> usize {
+        self.players
+    }
+
+    /// Side of the square board
+    pub fn board_dim(&self) -> usize {
+        self.board_dim
+    }
+
+    /// Cells of the board, one bit of the initial eggs each
+    pub fn board_size(&self) -> usize {
+        self.board_dim * self.board_dim
+    }
+
+    /// How long a player has to take the action of a round. Only advertised: the cli shows
+    /// it when an action is due, nothing counts it down and the server doesn't enforce it.
+    pub fn round_timer(&self) -> Option<Duration> {
+        self.round_timer_secs.map(Duration::from_secs)
+    }
+
+    pub fn edge(&self) -> EdgeBehavior {
+        self.edge
+    }
+
+    /// Rounds played before the game is over, none plays on until the server stops
+    pub fn max_rounds(&self) -> Option<usize> {
+        self.max_rounds
+    }
+
+    pub fn is_on_board(&self, x: u8, y: u8) -> bool {
+        (x as usize) < self.board_dim && (y as usize) < self.board_dim
+    }
+}
+
+impl Display for GameConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (players, dim) = (self.players, self.board_dim);
+        write!(f, "{players} players on a {dim}x{dim} board")?;
+        if let Some(secs) = self.round_timer_secs {
+            write!(f, ", {secs}s per round")?;
+        }
+        if let Some(rounds) = self.max_rounds {
+            write!(f, ", {rounds} rounds")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parameters of a [`GameConfig`], the ones left out are the defaults. The server takes
+/// them from the `game` key of its configuration.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(crate = "rocket::serde", default)]
+pub struct GameConfigBuilder {
+    players: Option<usize>,
+    board_dim: Option<usize>,
+    round_timer_secs: Option<u64>,
+    edge: Option<EdgeBehavior>,
+    max_rounds: Option<usize>,
+}
+
+impl GameConfigBuilder {
+    pub fn players(self, players: usize) -> Self {
+        Self {
+            players: Some(players),
+            ..self
+        }
+    }
+
+    pub fn board_dim(self, board_dim: usize) -> Self {
+        Self {
+            board_dim: Some(board_dim),
+            ..self
+        }
+    }
+
+    pub fn round_timer(self, round_timer: Duration) -> Self {
+        Self {
+            round_timer_secs: Some(round_timer.as_secs()),
+            ..self
+        }
+    }
+
+    pub fn edge(self, edge: EdgeBehavior) -> Self {
+        Self {
+            edge: Some(edge),
+            ..self
+        }
+    }
+
+    pub fn max_rounds(self, max_rounds: usize) -> Self {
+        Self {
+            max_rounds: Some(max_rounds),
+            ..self
+        }
+    }
+
+    /// The config, if the circuits can play it. They are compiled for one number of
+    /// players and one board.
+    pub fn build(self) -> Result<GameConfig, Error> {
+        let default = GameConfig::default();
+        let config = GameConfig {
+            players: self.players.unwrap_or(default.players),
+            board_dim: self.board_dim.unwrap_or(default.board_dim),
+            round_timer_secs: self.round_timer_secs,
+            edge: self.edge.unwrap_or(default.edge),
+            max_rounds: self.max_rounds,
+        };
+        let unsupported = |reason: String| Err(ProtocolError::UnsupportedConfig { reason }.into());
+        if config.players != TOTAL_USERS {
+            return unsupported(format!(
+                "{} players, the circuits take {TOTAL_USERS}",
+                config.players
+            ));
+        }
+        if config.board_dim != BOARD_DIM {
+            let dim = config.board_dim;
+            return unsupported(format!(
+                "a {dim}x{dim} board, the circuits take {BOARD_DIM}x{BOARD_DIM}"
+            ));
+        }
+        if config.round_timer_secs == Some(0) {
+            return unsupported("no time to act".to_string());
+        }
+        if config.max_rounds == Some(0) {
+            return unsupported("no round to play".to_string());
+        }
+        Ok(config)
+    }
+}
@@ -2,11 +2,13 @@ use itertools::Itertools;
 use rocket::serde::{Deserialize, Serialize};
 use tabled::settings::Style;
 use tabled::{Table, Tabled};
+use utoipa::ToSchema;
 
-use crate::types::{ServerState, UserRecord};
+use crate::config::GameConfig;
+use crate::types::{ServerState, UserRecord, UserStorage};
 use crate::UserId;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 #[serde(crate = "rocket::serde")]
 pub enum UserStatus {
     IDAcquired,
@@ -20,12 +22,18 @@ impl std::fmt::Display for UserStatus {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Tabled)]
+#[derive(Debug, Clone, Serialize, Deserialize, Tabled, ToSchema)]
 #[serde(crate = "rocket::serde")]
 pub struct RegisteredUser {
     pub id: UserId,
     pub name: String,
     pub status: UserStatus,
+    /// The server has their server key share
+    pub sks_received: bool,
+    /// They requested an action this round
+    pub action_submitted: bool,
+    /// They submitted their decryption share of this round's output
+    pub share_submitted: bool,
 }
 
 impl RegisteredUser {
@@ -34,6 +42,9 @@ impl RegisteredUser {
             id,
             name: name.to_string(),
             status: UserStatus::IDAcquired,
+            sks_received: false,
+            action_submitted: false,
+            share_submitted: false,
         }
     }
 }
@@ -57,25 +68,75 @@ impl From<&UserRecord> for RegisteredUser {
             id: user.id,
             name: user.name.to_string(),
             status,
+            // Key shares are dropped once the game is set up
+            sks_received: !matches!(user.storage, UserStorage::Empty),
+            action_submitted: user.action_submitted,
+            share_submitted: matches!(user.storage, UserStorage::DecryptionShare(Some(_))),
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Everything about the game a client, a bot or a spectator decides on
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct Dashboard {
     status: ServerState,
     users: Vec<RegisteredUser>,
     round: usize,
+    config: GameConfig,
 }
 impl Dashboard {
-    pub(crate) fn new(status: &ServerState, users: &[RegisteredUser], round: usize) -> Self {
+    pub(crate) fn new(
+        status: &ServerState,
+        users: &[RegisteredUser],
+        round: usize,
+        config: &GameConfig,
+    ) -> Self {
         Self {
             status: status.clone(),
             users: users.to_vec(),
             round,
+            config: config.clone(),
         }
     }
 
+    pub fn phase(&self) -> &ServerState {
+        &self.status
+    }
+
+    pub fn round(&self) -> usize {
+        self.round
+    }
+
+    pub fn users(&self) -> &[RegisteredUser] {
+        &self.users
+    }
+
+    pub fn user(&self, user_id: UserId) -> Option<&RegisteredUser> {
+        self.users.iter().find(|user| user.id == user_id)
+    }
+
+    pub fn is_registered(&self, user_id: UserId) -> bool {
+        self.user(user_id).is_some()
+    }
+
+    pub fn config(&self) -> &GameConfig {
+        &self.config
+    }
+
+    /// Players the game takes, registration closes once they joined
+    pub fn total_users(&self) -> usize {
+        self.config.players()
+    }
+
+    pub fn board_dim(&self) -> usize {
+        self.config.board_dim()
+    }
+
+    /// The dashboard as the server answers it with `Accept: application/json`
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("dashboards serialize")
+    }
+
     pub fn get_names(&self) -> Vec<String> {
         self.users
             .iter()
@@ -123,12 +184,35 @@ impl Dashboard {
         self.status == ServerState::ReadyForActions || self.round > round
     }
 
+    pub fn is_game_over(&self) -> bool {
+        self.status == ServerState::GameOver
+    }
+
     pub fn print_presentation(&self) {
         println!("action no. {}", self.round);
         println!("🤖🧠 {}", self.status);
+        println!("{} joined, {}", self.users.len(), self.config);
         let users = Table::new(&self.users)
             .with(Style::ascii_rounded())
             .to_string();
         println!("{}", users);
     }
 }
+
+/// Status of the current FHE run
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct FheStatus {
+    pub status: ServerState,
+    /// The user whose cell the run outputs, the one who triggered it
+    pub output_user: Option<UserId>,
+}
+
+impl FheStatus {
+    pub fn print_presentation(&self) {
+        println!("🤖🧠 {}", self.status);
+        if let Some(user_id) = self.output_user {
+            println!("Output: cell of user #{}", user_id);
+        }
+    }
+}
@@ -0,0 +1,206 @@
+//! Errors of the server and of the client. The server answers them as json bodies, their
+//! `code` and `message` next to their fields, which the client decodes back into the same
+//! [`Error`].
+
+use crate::telemetry::RequestId;
+use crate::types::UserId;
+use rocket::http::Status;
+use rocket::response::{self, Responder};
+use rocket::serde::json::Json;
+use rocket::serde::{Deserialize, Serialize};
+use rocket::Request;
+use std::fmt::Debug;
+use thiserror::Error;
+use utoipa::openapi::{ObjectBuilder, RefOr, Schema, Type};
+use utoipa::{PartialSchema, ToSchema};
+
+#[derive(Debug, Clone, PartialEq, Eq, Error, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", untagged)]
+pub enum Error {
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+    #[error(transparent)]
+    Phase(#[from] PhaseError),
+    #[error(transparent)]
+    Crypto(#[from] CryptoError),
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    /// The server couldn't be reached. Only the client has these, they aren't sent
+    #[error("Transport error: {0}")]
+    #[serde(skip)]
+    Transport(String),
+}
+
+/// A request the server can't take, or an answer the client can't take
+#[derive(Debug, Clone, PartialEq, Eq, Error, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", tag = "code", rename_all = "snake_case")]
+pub enum ProtocolError {
+    #[error("User #{user_id} is unregistered")]
+    UnregisteredUser { user_id: UserId },
+    #[error("Output not ready")]
+    OutputNotReady,
+    #[error("Decryption share from user {user_id} not found")]
+    DecryptionShareNotFound { user_id: UserId },
+    #[error("Malformed {payload}: expect {expect} bits but got {got}")]
+    MalformedPayload {
+        payload: String,
+        expect: usize,
+        got: usize,
+    },
+    /// A word that doesn't key switch, like one encrypted under other parameters
+    #[error("Undecodable {payload}")]
+    UndecodablePayload { payload: String },
+    #[error("Starting coordinates ({x}, {y}) are off the board")]
+    OffBoard { x: u8, y: u8 },
+    #[error("Unsupported game config: {reason}")]
+    UnsupportedConfig { reason: String },
+    /// Turned down before getting to a route, like a body that doesn't decode
+    #[error("Rejected with {status}: {reason}")]
+    Rejected { status: u16, reason: String },
+    #[error("Unexpected response {status}: {reason}")]
+    UnexpectedResponse { status: u16, reason: String },
+}
+
+/// A step out of turn, of the server or of a [`GameClient`](crate::GameClient)
+#[derive(Debug, Clone, PartialEq, Eq, Error, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", tag = "code", rename = "wrong_phase")]
+#[error("Wrong phase: expect {expected} but got {actual}")]
+pub struct PhaseError {
+    pub expected: String,
+    pub actual: String,
+}
+
+impl PhaseError {
+    pub(crate) fn new(expected: impl Debug, actual: impl Debug) -> Self {
+        Self {
+            expected: format!("{:?}", expected),
+            actual: format!("{:?}", actual),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", tag = "code", rename_all = "snake_case")]
+pub enum CryptoError {
+    #[error("Digest mismatch on {payload} from user #{user_id}, please re-upload")]
+    DigestMismatch { payload: String, user_id: UserId },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde", tag = "code", rename_all = "snake_case")]
+pub enum StorageError {
+    #[error("The ciphertext from user #{user_id} not found")]
+    CipherNotFound { user_id: UserId },
+    #[error("Init action not performed yet")]
+    GameNotInitedYet,
+    #[error("Cells not found")]
+    CellNotFound,
+    #[error("I/O error: {reason}")]
+    Io { reason: String },
+    /// A recorded fixture that can't be read or doesn't match the session
+    #[error("Fixture {fixture}: {reason}")]
+    Fixture { fixture: String, reason: String },
+}
+
+impl Error {
+    pub(crate) fn status(&self) -> Status {
+        match self {
+            Error::Protocol(error) => match error {
+                ProtocolError::UnregisteredUser { .. }
+                | ProtocolError::OutputNotReady
+                | ProtocolError::DecryptionShareNotFound { .. } => Status::NotFound,
+                ProtocolError::MalformedPayload { .. }
+                | ProtocolError::UndecodablePayload { .. }
+                | ProtocolError::OffBoard { .. }
+                | ProtocolError::UnsupportedConfig { .. } => Status::BadRequest,
+                ProtocolError::Rejected { status, .. } => {
+                    Status::from_code(*status).unwrap_or(Status::BadRequest)
+                }
+                ProtocolError::UnexpectedResponse { .. } => Status::InternalServerError,
+            },
+            Error::Phase(_) => Status::Conflict,
+            Error::Crypto(CryptoError::DigestMismatch { .. }) => Status::BadRequest,
+            Error::Storage(_) | Error::Transport(_) => Status::InternalServerError,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        StorageError::Io {
+            reason: error.to_string(),
+        }
+        .into()
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Self {
+        Error::Transport(error.to_string())
+    }
+}
+
+impl From<rmp_serde::encode::Error> for Error {
+    fn from(error: rmp_serde::encode::Error) -> Self {
+        Error::Transport(error.to_string())
+    }
+}
+
+/// Body of every error response
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub(crate) struct ErrorBody {
+    #[serde(flatten)]
+    pub(crate) error: Error,
+    pub(crate) message: String,
+    /// ID of the request in the logs of the server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) request_id: Option<String>,
+}
+
+impl ErrorBody {
+    pub(crate) fn new(error: Error, request_id: Option<String>) -> Self {
+        let message = error.to_string();
+        Self {
+            error,
+            message,
+            request_id,
+        }
+    }
+}
+
+impl PartialSchema for ErrorBody {
+    fn schema() -> RefOr<Schema> {
+        ObjectBuilder::new()
+            .description(Some(
+                "`code` names the error, the fields it has come next to `code` and `message`",
+            ))
+            .property("code", ObjectBuilder::new().schema_type(Type::String))
+            .property("message", ObjectBuilder::new().schema_type(Type::String))
+            .property("request_id", ObjectBuilder::new().schema_type(Type::String))
+            .required("code")
+            .required("message")
+            .into()
+    }
+}
+
+impl ToSchema for ErrorBody {}
+
+/// Answers an [`Error`] with its status and body
+pub(crate) struct ErrorResponse(pub(crate) Error);
+
+impl From<Error> for ErrorResponse {
+    fn from(error: Error) -> Self {
+        Self(error)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ErrorResponse {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let status = self.0.status();
+        let request_id = RequestId::of(req);
+        tracing::warn!(%request_id, status = status.code, "{}", self.0);
+        let body = ErrorBody::new(self.0, Some(request_id.0.clone()));
+        (status, Json(body)).respond_to(req)
+    }
+}
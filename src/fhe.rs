@@ -0,0 +1,30 @@
+//! The FHE backend, phantom-zone. The `sim` feature swaps it for the plaintext stand-ins in
+//! [`crate::sim`], which exposes the same items.
+
+use crate::types::{Seed, UserId};
+use phantom_zone::{
+    evaluator::NonInteractiveMultiPartyCrs,
+    keys::CommonReferenceSeededNonInteractiveMultiPartyServerKeyShare, parameters::BoolParameters,
+    KeySwitchWithId, NonInteractiveSeededFheBools, SampleExtractor,
+};
+
+pub use phantom_zone::{
+    aggregate_server_key_shares, gen_client_key, gen_server_key_share, get_active_parameter_set,
+    set_common_reference_seed, set_parameter_set, ClientKey, Encryptor, FheBool,
+    MultiPartyDecryptor, ParameterSelector,
+};
+
+pub type ServerKeyShare = CommonReferenceSeededNonInteractiveMultiPartyServerKeyShare<
+    Vec<Vec<u64>>,
+    BoolParameters<u64>,
+    NonInteractiveMultiPartyCrs<Seed>,
+>;
+
+pub type EncryptedWord = NonInteractiveSeededFheBools<Vec<u64>, Seed>;
+
+/// Key switch a word encrypted by `user_id` so the server can evaluate it
+pub fn unpack_word(word: &EncryptedWord, user_id: UserId) -> Vec<FheBool> {
+    word.unseed::<Vec<Vec<u64>>>()
+        .key_switch(user_id)
+        .extract_all()
+}
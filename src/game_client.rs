@@ -0,0 +1,479 @@
+//! The protocol choreography of a player, from registration to decrypting the output of
+//! every round, for frontends that don't want to drive [`WebClient`] call by call.
+//!
+//! Methods taking a step of the protocol only run in the [`Phase`] they belong to. Steps
+//! waiting on other players are taken by [`GameClient::refresh`], which checks the server
+//! and moves on once they are done.
+
+use crate::{
+    circuit::{gen_server_key_share, with_thread_pool},
+    client::{write_sks_submission, WebClient},
+    config::GameConfig,
+    dashboard::UserStatus,
+    error::{Error, PhaseError, ProtocolError},
+    fhe::gen_client_key,
+    server::setup,
+    types::{
+        CircuitOutput, ClientKey, DecryptionShare, Direction, GameStateLocalView, ServerState,
+        UserId,
+    },
+};
+use rocket::serde::{Deserialize, Serialize};
+use std::{fmt::Display, path::Path};
+
+/// What a player does on their turn
+#[derive(Clone, Copy, Debug)]
+pub enum Action {
+    Move(Direction),
+    LayEgg,
+    PickupEgg,
+    GetCell,
+}
+
+/// Where a [`GameClient`] is in the protocol
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    Unregistered,
+    /// Registered, waiting for the other players to join
+    Registered,
+    /// Every player joined, ready to submit our server key share
+    RegistrationClosed,
+    /// Waiting for the other server key shares
+    SubmittedKeyShare,
+    /// Every server key share is in, ready to set up the game
+    ReadyForSetup,
+    /// Waiting for the other players to set their starting coordinates
+    SetupGame,
+    /// Ready to take an action, unless another player takes one first
+    ReadyForAction,
+    /// The server is running FHE on our action, or on the one of another player
+    RunningFhe,
+    CompletedFhe,
+    /// Our own output, waiting for the decryption shares of the other players
+    AwaitingShares,
+    /// Our part of the round is done, ready to finish it
+    Decrypted,
+    /// Waiting for the other players to finish the round
+    NewRound,
+    /// The last round of the game is done
+    GameOver,
+}
+
+impl Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{{{{ {:?} }}}}", self)
+    }
+}
+
+/// Our keys, once registered
+struct Player {
+    ck: ClientKey,
+    user_id: UserId,
+}
+
+/// What a restarted client needs to pick its game up again, see [`GameClient::resume`].
+/// Holds the client key, keep it somewhere safe.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Session {
+    user_id: UserId,
+    ck: ClientKey,
+    view: Option<GameStateLocalView>,
+}
+
+pub struct GameClient {
+    client: WebClient,
+    name: String,
+    player: Option<Player>,
+    /// The game the server plays, known once registered
+    config: GameConfig,
+    /// Threads for decryption share generation, 0 uses every core
+    threads: usize,
+    phase: Phase,
+    names: Vec<String>,
+    view: Option<GameStateLocalView>,
+    round: usize,
+    /// Whether we took the action of this round, only then the output is ours to decrypt
+    is_my_action: bool,
+    fhe_out: Option<CircuitOutput>,
+    my_share: Option<DecryptionShare>,
+    output: Option<Vec<bool>>,
+}
+
+impl GameClient {
+    pub fn new(client: WebClient, name: &str) -> Self {
+        Self {
+            client,
+            name: name.to_string(),
+            player: None,
+            config: GameConfig::default(),
+            threads: 0,
+            phase: Phase::Unregistered,
+            names: vec![],
+            view: None,
+            round: 0,
+            is_my_action: false,
+            fhe_out: None,
+            my_share: None,
+            output: None,
+        }
+    }
+
+    /// Threads for decryption share generation, 0 uses every core
+    pub fn with_threads(self, threads: usize) -> Self {
+        Self { threads, ..self }
+    }
+
+    pub fn client(&self) -> &WebClient {
+        &self.client
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn user_id(&self) -> Option<UserId> {
+        self.player.as_ref().map(|player| player.user_id)
+    }
+
+    pub fn config(&self) -> &GameConfig {
+        &self.config
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// Names of every player, known once the registration is closed
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// Where we are and the eggs we laid, known once the game is set up
+    pub fn view(&self) -> Option<&GameStateLocalView> {
+        self.view.as_ref()
+    }
+
+    pub fn round(&self) -> usize {
+        self.round
+    }
+
+    pub fn is_my_action(&self) -> bool {
+        self.is_my_action
+    }
+
+    /// Our cell, after decrypting the output of our own action
+    pub fn output(&self) -> Option<&[bool]> {
+        self.output.as_deref()
+    }
+
+    fn ensure(&self, expected: Phase) -> Result<(), Error> {
+        if self.phase != expected {
+            return Err(PhaseError::new(expected, self.phase).into());
+        }
+        Ok(())
+    }
+
+    fn player(&self) -> Result<&Player, Error> {
+        self.player
+            .as_ref()
+            .ok_or_else(|| PhaseError::new(Phase::Registered, self.phase).into())
+    }
+
+    /// Set up the CRS the server uses, generate a client key and register. `resume` is
+    /// an ID our name got from this server before, it is kept if the server still knows
+    /// the name under it.
+    ///
+    /// Only a registration can be resumed this way, under a new client key. Once
+    /// registration closes, the key share of the ID is or will be from the old key, so
+    /// resuming is a [`PhaseError`]. [`GameClient::resume`] takes the old key back.
+    pub async fn register(&mut self, resume: Option<UserId>) -> Result<UserId, Error> {
+        self.ensure(Phase::Unregistered)?;
+        let param = self.client.get_param().await?;
+        setup(&param.seed);
+        self.config = param.config;
+        let ck = gen_client_key();
+        let dashboard = self.client.get_dashboard().await?;
+        let resumed = resume.filter(|user_id| {
+            dashboard
+                .user(*user_id)
+                .is_some_and(|user| user.name == self.name)
+        });
+        let user_id = match resumed {
+            Some(_) if *dashboard.phase() != ServerState::ReadyForJoining => {
+                return Err(PhaseError::new(ServerState::ReadyForJoining, dashboard.phase()).into())
+            }
+            Some(user_id) => user_id,
+            None => self.client.register(&self.name).await?.id,
+        };
+        self.player = Some(Player { ck, user_id });
+        self.phase = Phase::Registered;
+        Ok(user_id)
+    }
+
+    /// Our keys and local view, once registered
+    pub fn session(&self) -> Option<Session> {
+        let Player { ck, user_id } = self.player.as_ref()?;
+        Some(Session {
+            user_id: *user_id,
+            ck: ck.clone(),
+            view: self.view.clone(),
+        })
+    }
+
+    /// Pick up the game of a restarted client from where the server is, with the keys
+    /// and local view saved in `session`. A run triggered by nobody yet is triggered if
+    /// the action is ours. The output of our own action is decrypted again.
+    pub async fn resume(&mut self, session: Session) -> Result<Phase, Error> {
+        self.ensure(Phase::Unregistered)?;
+        let Session { user_id, ck, view } = session;
+        let param = self.client.get_param().await?;
+        setup(&param.seed);
+        self.config = param.config;
+        let dashboard = self.client.get_dashboard().await?;
+        let me = dashboard
+            .user(user_id)
+            .filter(|user| user.name == self.name)
+            .ok_or(ProtocolError::UnregisteredUser { user_id })?;
+
+        self.phase = match dashboard.phase() {
+            ServerState::ReadyForJoining => Phase::Registered,
+            ServerState::ReadyForServerKeyShares if me.sks_received => Phase::SubmittedKeyShare,
+            ServerState::ReadyForServerKeyShares => Phase::RegistrationClosed,
+            ServerState::ReadyForSetupGame
+                if matches!(me.status, UserStatus::StartingCoordsSubmitted) =>
+            {
+                Phase::SetupGame
+            }
+            ServerState::ReadyForSetupGame => Phase::ReadyForSetup,
+            ServerState::ReadyForActions => Phase::ReadyForAction,
+            ServerState::ReadyForRunning => {
+                if me.action_submitted {
+                    self.client.trigger_fhe_run(user_id).await?;
+                }
+                self.is_my_action = me.action_submitted;
+                Phase::RunningFhe
+            }
+            ServerState::RunningFhe | ServerState::CompletedFhe => {
+                let status = self.client.get_fhe_status().await?;
+                self.is_my_action = status.output_user == Some(user_id);
+                match status.status {
+                    ServerState::CompletedFhe if !self.is_my_action && me.share_submitted => {
+                        Phase::Decrypted
+                    }
+                    ServerState::CompletedFhe => Phase::CompletedFhe,
+                    _ => Phase::RunningFhe,
+                }
+            }
+            ServerState::GameOver => Phase::GameOver,
+        };
+        if self.phase != Phase::Registered {
+            self.names = dashboard.get_names();
+        }
+        self.round = dashboard.round();
+        self.view = view;
+        self.player = Some(Player { ck, user_id });
+        Ok(self.phase)
+    }
+
+    /// Move on if what we are waiting for is done, then answer where we are.
+    /// Phases that wait on nobody stay as they are.
+    pub async fn refresh(&mut self) -> Result<Phase, Error> {
+        let client = &self.client;
+        match self.phase {
+            Phase::Registered => {
+                let dashboard = client.get_dashboard().await?;
+                if dashboard.is_concluded() {
+                    self.names = dashboard.get_names();
+                    self.phase = Phase::RegistrationClosed;
+                }
+            }
+            Phase::SubmittedKeyShare => {
+                let dashboard = client.get_dashboard().await?;
+                if dashboard.is_submit_sks_complete() {
+                    self.phase = Phase::ReadyForSetup;
+                }
+            }
+            Phase::SetupGame => {
+                let dashboard = client.get_dashboard().await?;
+                if dashboard.is_setup_game_complete() {
+                    self.phase = Phase::ReadyForAction;
+                }
+            }
+            Phase::ReadyForAction => {
+                let dashboard = client.get_dashboard().await?;
+                if dashboard.is_fhe_ongoing() {
+                    self.start_run(false);
+                }
+            }
+            Phase::RunningFhe => {
+                let status = client.get_fhe_status().await?;
+                if status.status == ServerState::CompletedFhe {
+                    self.phase = Phase::CompletedFhe;
+                }
+            }
+            Phase::AwaitingShares => {
+                let user_id = self.player()?.user_id;
+                let dashboard = client.get_dashboard().await?;
+                if dashboard.is_decryption_shares_submission_complete(user_id) {
+                    self.output = Some(self.decrypt_output().await?);
+                    self.fhe_out = None;
+                    self.my_share = None;
+                    self.phase = Phase::Decrypted;
+                }
+            }
+            Phase::NewRound => {
+                let dashboard = client.get_dashboard().await?;
+                if dashboard.is_game_over() {
+                    self.phase = Phase::GameOver;
+                } else if dashboard.is_ready_for_actions(self.round) {
+                    self.round += 1;
+                    self.phase = Phase::ReadyForAction;
+                }
+            }
+            _ => {}
+        }
+        Ok(self.phase)
+    }
+
+    /// Generate and submit our server key share. With `file`, the share is saved there
+    /// and uploaded from it, so it needn't stay in memory.
+    pub async fn submit_key_share(&mut self, file: Option<&Path>) -> Result<(), Error> {
+        self.ensure(Phase::RegistrationClosed)?;
+        let Player { ck, user_id } = self.player()?;
+        let (ck, user_id) = (ck.clone(), *user_id);
+        let total_users = self.names.len();
+        // Long running, keep it off the async runtime
+        let sks = async move {
+            tokio::task::spawn_blocking(move || gen_server_key_share(user_id, total_users, &ck))
+                .await
+                .expect("key share generation doesn't panic")
+        };
+        match file {
+            Some(path) => {
+                // Only a temporary, freed before the upload streams it back from the file
+                write_sks_submission(path, user_id, &sks.await)?;
+                self.client.submit_sks_from_file(path).await?;
+            }
+            None => {
+                self.client.submit_sks(user_id, &sks.await).await?;
+            }
+        }
+        self.phase = Phase::SubmittedKeyShare;
+        Ok(())
+    }
+
+    /// Start a game on an empty board, from row `x` and column `y`
+    pub async fn setup_game(&mut self, x: u8, y: u8) -> Result<(), Error> {
+        self.ensure(Phase::ReadyForSetup)?;
+        if !self.config.is_on_board(x, y) {
+            return Err(ProtocolError::OffBoard { x, y }.into());
+        }
+        let Player { ck, user_id } = self.player()?;
+        let user_id = *user_id;
+        let initial_eggs = vec![false; self.config.board_size()];
+        self.client.init_game(ck, user_id, &initial_eggs).await?;
+        self.client
+            .set_starting_coords(ck, user_id, &(x, y))
+            .await?;
+        self.view = Some(GameStateLocalView::new(x, y, user_id, &self.config));
+        self.phase = Phase::SetupGame;
+        Ok(())
+    }
+
+    /// Take `action` and trigger the FHE run. Answers false if another player took an
+    /// action first, theirs is run this round and ours didn't take effect.
+    pub async fn act(&mut self, action: Action) -> Result<bool, Error> {
+        self.ensure(Phase::ReadyForAction)?;
+        let Player { ck, user_id } = self.player()?;
+        let (client, user_id) = (&self.client, *user_id);
+        let requested = match action {
+            Action::Move(direction) => client.move_player(ck, user_id, direction).await,
+            Action::LayEgg => client.lay_egg(user_id).await,
+            Action::PickupEgg => client.pickup_egg(user_id).await,
+            Action::GetCell => client.get_cell(user_id).await,
+        };
+        if let Err(err) = requested {
+            if matches!(err, Error::Phase(_)) && client.get_dashboard().await?.is_fhe_ongoing() {
+                self.start_run(false);
+                return Ok(false);
+            }
+            return Err(err);
+        }
+        if let Some(view) = &mut self.view {
+            match action {
+                Action::Move(direction) => view.move_player(direction),
+                Action::LayEgg => view.lay(),
+                Action::PickupEgg => view.pickup(),
+                Action::GetCell => {}
+            }
+        }
+        self.client.trigger_fhe_run(user_id).await?;
+        self.start_run(true);
+        Ok(true)
+    }
+
+    fn start_run(&mut self, is_my_action: bool) {
+        self.is_my_action = is_my_action;
+        self.output = None;
+        self.phase = Phase::RunningFhe;
+    }
+
+    /// Download the output of the run and generate our decryption share. The output of
+    /// another player gets our share. Ours keeps it and waits for the shares of the
+    /// other players, [`GameClient::refresh`] decrypts it once they are in.
+    pub async fn decrypt(&mut self) -> Result<(), Error> {
+        self.ensure(Phase::CompletedFhe)?;
+        let Player { ck, user_id } = self.player()?;
+        let (client, ck, user_id) = (&self.client, ck.clone(), *user_id);
+        let state = client.trigger_fhe_run(user_id).await?;
+        if state != ServerState::CompletedFhe {
+            return Err(PhaseError::new(ServerState::CompletedFhe, state).into());
+        }
+        let fhe_out = client.get_fhe_output().await?;
+        let threads = self.threads;
+        let (fhe_out, share) = tokio::task::spawn_blocking(move || {
+            let share = with_thread_pool(threads, || fhe_out.gen_decryption_share(&ck));
+            (fhe_out, share)
+        })
+        .await
+        .expect("decryption share generation doesn't panic");
+
+        if self.is_my_action {
+            self.fhe_out = Some(fhe_out);
+            self.my_share = Some(share);
+            self.phase = Phase::AwaitingShares;
+        } else {
+            client
+                .submit_decryption_share(user_id, &(self.round, share))
+                .await?;
+            self.phase = Phase::Decrypted;
+        }
+        Ok(())
+    }
+
+    /// Our output, with our share and the ones of the other players
+    async fn decrypt_output(&self) -> Result<Vec<bool>, Error> {
+        let Player { ck, user_id } = self.player()?;
+        let (Some(fhe_out), Some(my_share)) = (&self.fhe_out, &self.my_share) else {
+            return Err(ProtocolError::OutputNotReady.into());
+        };
+        let mut shares = vec![];
+        for other in 0..self.names.len() {
+            if other == *user_id {
+                shares.push(my_share.clone());
+            } else {
+                shares.push(self.client.get_decryption_share(self.round, other).await?);
+            }
+        }
+        Ok(fhe_out.decrypt(ck, &shares))
+    }
+
+    /// Tell the server we are done with this round
+    pub async fn finish_round(&mut self) -> Result<(), Error> {
+        self.ensure(Phase::Decrypted)?;
+        self.client.done(self.player()?.user_id).await?;
+        self.phase = Phase::NewRound;
+        Ok(())
+    }
+}
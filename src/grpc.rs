@@ -0,0 +1,577 @@
+//! The API over gRPC, as described in `proto/chickens.proto`. The service runs the handlers
+//! of the rocket routes on the same storage, [`GrpcClient`] speaks it behind [`WebClient`]
+//! for `grpc://` urls.
+//!
+//! [`WebClient`]: crate::WebClient
+// tonic's `Status` is the error of every call
+#![allow(clippy::result_large_err)]
+
+use crate::client::{upload_stream, RawResponse, RequestBody, ResponseBody};
+use crate::dashboard::FheStatus;
+use crate::error::{Error, ErrorBody, ProtocolError};
+use crate::guards::spawn_decoder;
+use crate::handlers;
+use crate::telemetry::{new_request_id, REQUEST_ID_HEADER};
+use crate::types::{
+    DecryptionShareSubmission, EncryptedWord, MutexServerStorage, ServerState, SksSubmission,
+    UserAction, UserId,
+};
+use futures::stream::{self, BoxStream, Stream, StreamExt};
+use proto::chickens_client::ChickensClient;
+use proto::chickens_server::{Chickens, ChickensServer};
+use proto::{ActionRequest, Chunk, DecryptionShareRequest, Empty, MsgPack, RegisterRequest};
+use rocket::data::{ByteUnit, Limits, ToByteUnit};
+use rocket::fairing::AdHoc;
+use rocket::http::Method;
+use rocket::serde::msgpack;
+use rocket::serde::{DeserializeOwned, Serialize};
+use std::future::ready;
+use std::io::{self, Cursor};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::fs::File;
+use tokio::io::AsyncRead;
+use tokio::net::TcpListener;
+use tokio::sync::OnceCell;
+use tonic::codegen::{http, BoxFuture, Context, Poll, Service as HttpService};
+use tonic::metadata::MetadataKey;
+use tonic::server::NamedService;
+use tonic::service::interceptor::InterceptedService;
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::{Channel, Endpoint, Server};
+use tonic::{Code, Request, Response, Status, Streaming};
+use tracing::{info, info_span, warn, Instrument};
+
+pub(crate) mod proto {
+    tonic::include_proto!("chickens");
+}
+
+/// Serves the gRPC API next to the rocket, on the storage of its routes, when `grpc_port`
+/// is configured
+pub(crate) fn fairing() -> AdHoc {
+    AdHoc::on_liftoff("gRPC", |rocket| {
+        Box::pin(async move {
+            let Ok(port) = rocket.figment().extract_inner::<u16>("grpc_port") else {
+                return;
+            };
+            let ss = rocket
+                .state::<MutexServerStorage>()
+                .expect("managed")
+                .clone();
+            let config = rocket.config();
+            let limit = config.limits.get("msgpack").unwrap_or(Limits::MESSAGE_PACK);
+            let address = SocketAddr::new(config.address, port);
+            let listener = match TcpListener::bind(address).await {
+                Ok(listener) => listener,
+                Err(err) => return warn!("gRPC can't listen on {address}: {err}"),
+            };
+            info!("gRPC listening on {address}");
+            tokio::spawn(async move {
+                if let Err(err) = serve(ss, listener, limit).await {
+                    warn!("gRPC server stopped: {err}");
+                }
+            });
+        })
+    })
+}
+
+/// `limit` caps streamed bodies, as the `msgpack` limit caps them over HTTP
+pub(crate) async fn serve(
+    ss: MutexServerStorage,
+    listener: TcpListener,
+    limit: ByteUnit,
+) -> Result<(), tonic::transport::Error> {
+    let incoming = TcpIncoming::from_listener(listener, true, None).expect("listener is bound");
+    Server::builder()
+        .add_service(WithRequestId(ChickensServer::new(Service { ss, limit })))
+        .serve_with_incoming(incoming)
+        .await
+}
+
+tokio::task_local! {
+    /// ID of the call being served, error bodies carry it as over HTTP
+    static REQUEST_ID: String;
+}
+
+/// Serves every call in a span with its ID, the one in its `x-request-id` metadata or a new
+/// one, and with the ID in [`REQUEST_ID`]
+#[derive(Clone)]
+struct WithRequestId<S>(S);
+
+impl<S: NamedService> NamedService for WithRequestId<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S, B> HttpService<http::Request<B>> for WithRequestId<S>
+where
+    S: HttpService<http::Request<B>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<S::Response, S::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let id = request.headers().get(REQUEST_ID_HEADER);
+        let id = id.and_then(|id| id.to_str().ok());
+        let id = id.map_or_else(new_request_id, str::to_string);
+        let span = info_span!("request", id, uri = %request.uri());
+        let response = self.0.call(request).instrument(span);
+        Box::pin(REQUEST_ID.scope(id, response))
+    }
+}
+
+struct Service {
+    ss: MutexServerStorage,
+    limit: ByteUnit,
+}
+
+/// Same meaning as the HTTP status of the error, the message is the body the HTTP API
+/// would answer
+impl From<Error> for Status {
+    fn from(error: Error) -> Self {
+        let code = match error.status().code {
+            400 => Code::InvalidArgument,
+            404 => Code::NotFound,
+            409 => Code::FailedPrecondition,
+            413 => Code::ResourceExhausted,
+            _ => Code::Internal,
+        };
+        let body = ErrorBody::new(error, REQUEST_ID.try_with(Clone::clone).ok());
+        let message = serde_json::to_string(&body).unwrap_or(body.message);
+        Status::new(code, message)
+    }
+}
+
+/// The error the server answered, or the call failing
+impl From<Status> for Error {
+    fn from(status: Status) -> Self {
+        match serde_json::from_str::<ErrorBody>(status.message()) {
+            Ok(body) => body.error,
+            Err(_) => Error::Transport(status.to_string()),
+        }
+    }
+}
+
+impl From<tonic::transport::Error> for Error {
+    fn from(error: tonic::transport::Error) -> Self {
+        Error::Transport(error.to_string())
+    }
+}
+
+/// A body that doesn't decode, as the HTTP API rejects it
+fn rejected(reason: impl ToString) -> Status {
+    let reason = reason.to_string();
+    Error::from(ProtocolError::Rejected {
+        status: 400,
+        reason,
+    })
+    .into()
+}
+
+fn encode(value: &impl Serialize) -> Result<MsgPack, Status> {
+    let body = msgpack::to_compact_vec(value).map_err(|err| Status::internal(err.to_string()))?;
+    Ok(MsgPack { body })
+}
+
+fn decode<T: DeserializeOwned>(body: &[u8]) -> Result<T, Status> {
+    msgpack::from_slice(body).map_err(rejected)
+}
+
+fn user_id(user_id: UserId) -> Response<proto::UserId> {
+    Response::new(proto::UserId {
+        user_id: user_id as u64,
+    })
+}
+
+fn action(request: Request<ActionRequest>) -> Result<(UserId, UserAction<EncryptedWord>), Status> {
+    let request = request.into_inner();
+    Ok((request.user_id as UserId, decode(&request.action)?))
+}
+
+#[tonic::async_trait]
+impl Chickens for Service {
+    async fn get_param(&self, _: Request<Empty>) -> Result<Response<MsgPack>, Status> {
+        let param = handlers::get_param(&self.ss).await;
+        Ok(Response::new(encode(&param)?))
+    }
+
+    async fn register(
+        &self,
+        request: Request<RegisterRequest>,
+    ) -> Result<Response<MsgPack>, Status> {
+        let user = handlers::register(&self.ss, &request.get_ref().name).await?;
+        Ok(Response::new(encode(&user)?))
+    }
+
+    async fn get_dashboard(&self, _: Request<Empty>) -> Result<Response<MsgPack>, Status> {
+        let dashboard = handlers::get_dashboard(&self.ss).await;
+        Ok(Response::new(encode(&dashboard)?))
+    }
+
+    async fn submit_sks(
+        &self,
+        request: Request<Streaming<Chunk>>,
+    ) -> Result<Response<proto::UserId>, Status> {
+        let (sender, decoder) = spawn_decoder::<SksSubmission<'static>>();
+        let mut chunks = request.into_inner();
+        let mut total = 0;
+        while let Some(chunk) = chunks.message().await? {
+            total += chunk.data.len();
+            if total.bytes() > self.limit {
+                return Err(Error::from(ProtocolError::Rejected {
+                    status: 413,
+                    reason: format!("data limit of {} exceeded", self.limit),
+                })
+                .into());
+            }
+            // The decoder hung up early, it has an error for us
+            if sender.send(chunk.data).await.is_err() {
+                break;
+            }
+        }
+        drop(sender);
+        let submission = decoder
+            .await
+            .expect("msgpack decoder doesn't panic")
+            .map_err(rejected)?;
+        Ok(user_id(handlers::submit_sks(&self.ss, submission).await?))
+    }
+
+    async fn setup_game(
+        &self,
+        request: Request<ActionRequest>,
+    ) -> Result<Response<proto::UserId>, Status> {
+        let (user, action) = action(request)?;
+        Ok(user_id(handlers::setup_game(&self.ss, user, action).await?))
+    }
+
+    async fn request_action(
+        &self,
+        request: Request<ActionRequest>,
+    ) -> Result<Response<proto::UserId>, Status> {
+        let (user, action) = action(request)?;
+        Ok(user_id(
+            handlers::request_action(&self.ss, user, action).await?,
+        ))
+    }
+
+    async fn done(
+        &self,
+        request: Request<ActionRequest>,
+    ) -> Result<Response<proto::UserId>, Status> {
+        let (user, action) = action(request)?;
+        Ok(user_id(handlers::done(&self.ss, user, action).await?))
+    }
+
+    async fn run(&self, request: Request<proto::UserId>) -> Result<Response<MsgPack>, Status> {
+        let user_id = request.get_ref().user_id as UserId;
+        let state = handlers::run(&self.ss, user_id).await?;
+        Ok(Response::new(encode(&state)?))
+    }
+
+    async fn get_fhe_status(&self, _: Request<Empty>) -> Result<Response<MsgPack>, Status> {
+        let status = handlers::get_fhe_status(&self.ss).await;
+        Ok(Response::new(encode(&status)?))
+    }
+
+    type WatchFheStream = BoxStream<'static, Result<MsgPack, Status>>;
+
+    async fn watch_fhe(&self, _: Request<Empty>) -> Result<Response<Self::WatchFheStream>, Status> {
+        let ss = self.ss.clone();
+        let changes = ss.lock().await.changes.subscribe();
+        let updates = stream::unfold(Some((ss, changes, true)), |watch| async move {
+            let (ss, mut changes, first) = watch?;
+            if !first {
+                changes.changed().await.ok()?;
+            }
+            let status = handlers::get_fhe_status(&ss).await;
+            let running = status.status == ServerState::RunningFhe;
+            Some((encode(&status), running.then_some((ss, changes, false))))
+        });
+        Ok(Response::new(updates.boxed()))
+    }
+
+    async fn get_fhe_output(&self, _: Request<Empty>) -> Result<Response<MsgPack>, Status> {
+        let output = handlers::get_fhe_output(&self.ss).await?;
+        Ok(Response::new(encode(&output)?))
+    }
+
+    async fn submit_decryption_share(
+        &self,
+        request: Request<MsgPack>,
+    ) -> Result<Response<proto::UserId>, Status> {
+        let submission: DecryptionShareSubmission = decode(&request.get_ref().body)?;
+        Ok(user_id(
+            handlers::submit_decryption_share(&self.ss, submission).await?,
+        ))
+    }
+
+    async fn get_decryption_share(
+        &self,
+        request: Request<DecryptionShareRequest>,
+    ) -> Result<Response<MsgPack>, Status> {
+        let DecryptionShareRequest { output_id, user_id } = request.into_inner();
+        let share =
+            handlers::get_decryption_share(&self.ss, output_id as usize, user_id as UserId).await?;
+        Ok(Response::new(encode(&share)?))
+    }
+}
+
+type RequestIdInterceptor = fn(Request<()>) -> Result<Request<()>, Status>;
+type Client = ChickensClient<InterceptedService<Channel, RequestIdInterceptor>>;
+
+/// Every call gets a request ID, like the HTTP requests of [`WebClient`](crate::WebClient)
+fn with_request_id(mut request: Request<()>) -> Result<Request<()>, Status> {
+    let key = MetadataKey::from_bytes(REQUEST_ID_HEADER.as_bytes()).expect("valid header name");
+    let id = new_request_id().parse().expect("request IDs are ascii");
+    request.metadata_mut().insert(key, id);
+    Ok(request)
+}
+
+/// Client of the gRPC service. It connects on the first call, the runtime may not be
+/// running yet when it is created.
+pub struct GrpcClient {
+    url: String,
+    channel: OnceCell<Channel>,
+}
+
+impl GrpcClient {
+    pub(crate) fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            channel: OnceCell::new(),
+        }
+    }
+
+    pub(crate) fn url(&self) -> String {
+        self.url.clone()
+    }
+
+    async fn client(&self) -> Result<Client, Error> {
+        let channel = self
+            .channel
+            .get_or_try_init(|| async {
+                let url = self.url.replacen("grpc://", "http://", 1);
+                Endpoint::from_shared(url)?.connect().await
+            })
+            .await?;
+        let with_request_id = with_request_id as RequestIdInterceptor;
+        Ok(ChickensClient::with_interceptor(
+            channel.clone(),
+            with_request_id,
+        ))
+    }
+
+    /// Makes the call standing for an HTTP request. Answers come back as the HTTP API
+    /// would answer them, so the rest of [`WebClient`](crate::WebClient) doesn't tell
+    /// the transports apart.
+    pub(crate) async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        body: RequestBody<'_>,
+    ) -> Result<RawResponse, Error> {
+        let call = Call::parse(method, path)?;
+        let mut client = self.client().await?;
+        let response = match call {
+            Call::GetParam => msgpack_response(client.get_param(Empty {}).await),
+            Call::Register => {
+                let name = String::from_utf8_lossy(&body_bytes(body).await?).into_owned();
+                msgpack_response(client.register(RegisterRequest { name }).await)
+            }
+            Call::GetDashboard => msgpack_response(client.get_dashboard(Empty {}).await),
+            Call::SubmitSks => {
+                let (chunks, read_error) = upload_chunks(body).await?;
+                let response = client.submit_sks(chunks).await;
+                // The server got a truncated body, the read error tells why
+                if let Some(err) = read_error.take() {
+                    return Err(err.into());
+                }
+                user_id_response(response)?
+            }
+            Call::SetupGame(user_id) | Call::RequestAction(user_id) | Call::Done(user_id) => {
+                let request = ActionRequest {
+                    user_id,
+                    action: body_bytes(body).await?,
+                };
+                let response = match call {
+                    Call::SetupGame(_) => client.setup_game(request).await,
+                    Call::RequestAction(_) => client.request_action(request).await,
+                    _ => client.done(request).await,
+                };
+                user_id_response(response)?
+            }
+            Call::Run(user_id) => msgpack_response(client.run(proto::UserId { user_id }).await),
+            Call::GetFheStatus => msgpack_response(client.get_fhe_status(Empty {}).await),
+            Call::GetFheOutput => msgpack_response(client.get_fhe_output(Empty {}).await),
+            Call::SubmitDecryptionShare => {
+                let body = body_bytes(body).await?;
+                user_id_response(client.submit_decryption_share(MsgPack { body }).await)?
+            }
+            Call::GetDecryptionShare { output_id, user_id } => {
+                let request = DecryptionShareRequest { output_id, user_id };
+                msgpack_response(client.get_decryption_share(request).await)
+            }
+        };
+        Ok(response)
+    }
+
+    /// Every status of the ongoing run as the server pushes it, until the run is over
+    pub(crate) async fn watch_fhe(
+        &self,
+    ) -> Result<BoxStream<'static, Result<FheStatus, Error>>, Error> {
+        let updates = self.client().await?.watch_fhe(Empty {}).await?.into_inner();
+        let updates = updates.map(|update| {
+            msgpack::from_slice(&update?.body).map_err(|err| {
+                let reason = err.to_string();
+                ProtocolError::UnexpectedResponse {
+                    status: 200,
+                    reason,
+                }
+                .into()
+            })
+        });
+        Ok(updates.boxed())
+    }
+}
+
+/// The call standing for an HTTP route
+#[derive(Clone, Copy)]
+enum Call {
+    GetParam,
+    Register,
+    GetDashboard,
+    SubmitSks,
+    SetupGame(u64),
+    RequestAction(u64),
+    Done(u64),
+    Run(u64),
+    GetFheStatus,
+    GetFheOutput,
+    SubmitDecryptionShare,
+    GetDecryptionShare { output_id: u64, user_id: u64 },
+}
+
+impl Call {
+    fn parse(method: Method, path: &str) -> Result<Self, Error> {
+        let no_call = || Error::Transport(format!("No gRPC call for {method} {path}"));
+        let id = |segment: &str| segment.parse().map_err(|_| no_call());
+        let segments = path.trim_start_matches('/').split('/').collect::<Vec<_>>();
+        Ok(match (method, segments.as_slice()) {
+            (Method::Get, ["param"]) => Self::GetParam,
+            (Method::Post, ["register"]) => Self::Register,
+            (Method::Get, ["dashboard"]) => Self::GetDashboard,
+            (Method::Post, ["submit_sks"]) => Self::SubmitSks,
+            (Method::Post, ["setup_game", user_id]) => Self::SetupGame(id(user_id)?),
+            (Method::Post, ["request_action", user_id]) => Self::RequestAction(id(user_id)?),
+            (Method::Post, ["done", user_id]) => Self::Done(id(user_id)?),
+            (Method::Post, ["run", user_id]) => Self::Run(id(user_id)?),
+            (Method::Get, ["fhe_status"]) => Self::GetFheStatus,
+            (Method::Get, ["fhe_output"]) => Self::GetFheOutput,
+            (Method::Post, ["submit_decryption_share"]) => Self::SubmitDecryptionShare,
+            (Method::Get, ["decryption_share", output_id, user_id]) => Self::GetDecryptionShare {
+                output_id: id(output_id)?,
+                user_id: id(user_id)?,
+            },
+            _ => return Err(no_call()),
+        })
+    }
+}
+
+async fn body_bytes(body: RequestBody<'_>) -> Result<Vec<u8>, Error> {
+    Ok(match body {
+        RequestBody::Empty => vec![],
+        RequestBody::Bytes(bytes) | RequestBody::MsgPack(bytes) => bytes,
+        RequestBody::MsgPackFile(path) => tokio::fs::read(path).await?,
+    })
+}
+
+/// The read error that ended an upload early
+#[derive(Clone, Default)]
+struct ReadError(Arc<Mutex<Option<io::Error>>>);
+
+impl ReadError {
+    fn set(&self, error: io::Error) {
+        *self.0.lock().unwrap() = Some(error);
+    }
+
+    fn take(&self) -> Option<io::Error> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+/// Chunks of a body too large for one message, with an upload progress bar. Not boxed,
+/// rustc can't tell a call streaming a boxed stream is `Send`.
+async fn upload_chunks(
+    body: RequestBody<'_>,
+) -> Result<(impl Stream<Item = Chunk> + Send + 'static, ReadError), Error> {
+    let (reader, total_bytes): (Box<dyn AsyncRead + Send + Unpin>, u64) = match body {
+        RequestBody::MsgPackFile(path) => {
+            let file = File::open(path).await?;
+            let total_bytes = file.metadata().await?.len();
+            (Box::new(file), total_bytes)
+        }
+        body => {
+            let bytes = body_bytes(body).await?;
+            let total_bytes = bytes.len() as u64;
+            (Box::new(Cursor::new(bytes)), total_bytes)
+        }
+    };
+    // The stream ends on a read error, which is kept for the caller
+    let read_error = ReadError::default();
+    let chunks = upload_stream(reader, total_bytes).scan(read_error.clone(), |error, chunk| {
+        ready(match chunk {
+            Ok(data) => Some(Chunk { data }),
+            Err(err) => {
+                error.set(err);
+                None
+            }
+        })
+    });
+    Ok((chunks, read_error))
+}
+
+fn msgpack_response(response: Result<Response<MsgPack>, Status>) -> RawResponse {
+    match response {
+        Ok(response) => RawResponse {
+            status: 200,
+            body: ResponseBody::MsgPack(response.into_inner().body),
+        },
+        Err(status) => error_response(status),
+    }
+}
+
+fn user_id_response(
+    response: Result<Response<proto::UserId>, Status>,
+) -> Result<RawResponse, Error> {
+    Ok(match response {
+        Ok(response) => {
+            let user_id = response.into_inner().user_id as UserId;
+            RawResponse {
+                status: 200,
+                body: ResponseBody::MsgPack(msgpack::to_compact_vec(&user_id)?),
+            }
+        }
+        Err(status) => error_response(status),
+    })
+}
+
+/// The HTTP API's answer to the same error
+fn error_response(status: Status) -> RawResponse {
+    let code = match status.code() {
+        Code::InvalidArgument => 400,
+        Code::NotFound => 404,
+        Code::FailedPrecondition => 409,
+        Code::ResourceExhausted => 413,
+        _ => 500,
+    };
+    RawResponse {
+        status: code,
+        body: ResponseBody::Json(status.message().to_string()),
+    }
+}
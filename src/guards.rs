@@ -0,0 +1,187 @@
+use bytes::{Bytes, BytesMut};
+use rocket::data::{self, Data, FromData, Limits, ToByteUnit};
+use rocket::http::{MediaType, Status};
+use rocket::response::{self, Responder};
+use rocket::serde::json::Json;
+use rocket::serde::msgpack::{Error, MsgPack};
+use rocket::serde::{DeserializeOwned, Serialize};
+use rocket::Request;
+use std::io::{self, Read};
+use std::ops::Deref;
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Chunks buffered between the request body and the decoder
+const CHUNKS_IN_FLIGHT: usize = 16;
+const CHUNK_SIZE: usize = 128 * 1024;
+
+/// Like rocket's [`MsgPack`](rocket::serde::msgpack::MsgPack) guard, but decodes the body as it
+/// streams in. Every msgpack body goes through it, so routes all answer bad bodies the same
+/// way. Rocket's guard buffers the whole raw body and keeps it cached for the lifetime of the
+/// request, on top of the decoded value. For a key share that is hundreds of MB.
+///
+/// Only the raw body is spared. The value is decoded into owned types as before, nothing
+/// borrows from the body: phantom-zone ciphertexts are `Vec<u64>`, which msgpack encodes
+/// with variable width integers.
+pub(crate) struct StreamedMsgPack<T>(pub(crate) T);
+
+impl<T> Deref for StreamedMsgPack<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned + Send + 'static> StreamedMsgPack<T> {
+    async fn from_data(req: &Request<'_>, data: Data<'_>) -> Result<Self, (Status, Error)> {
+        let limit = req.limits().get("msgpack").unwrap_or(Limits::MESSAGE_PACK);
+        let (sender, decoder) = spawn_decoder::<T>();
+
+        // A byte over the limit tells a body of exactly the limit from a longer one
+        let mut stream = data.open(limit + 1);
+        let mut total = 0;
+        loop {
+            let mut chunk = BytesMut::with_capacity(CHUNK_SIZE);
+            let read = stream
+                .read_buf(&mut chunk)
+                .await
+                .map_err(|e| (Status::BadRequest, Error::InvalidDataRead(e)))?;
+            if read == 0 {
+                break;
+            }
+            total += read;
+            // The decoder hung up early, it has an error for us
+            if sender.send(chunk.freeze()).await.is_err() {
+                break;
+            }
+        }
+        drop(sender);
+
+        if total.bytes() > limit {
+            let eof = io::ErrorKind::UnexpectedEof;
+            let error = Error::InvalidDataRead(io::Error::new(eof, "data limit exceeded"));
+            return Err((Status::PayloadTooLarge, error));
+        }
+
+        match decoder.await.expect("msgpack decoder doesn't panic") {
+            Ok(value) => Ok(StreamedMsgPack(value)),
+            // Unlike rocket's guard, a truncated body is a bad request. The limit is checked above
+            Err(e @ Error::TypeMismatch(_))
+            | Err(e @ Error::OutOfRange)
+            | Err(e @ Error::LengthMismatch(_)) => Err((Status::UnprocessableEntity, e)),
+            Err(e) => Err((Status::BadRequest, e)),
+        }
+    }
+}
+
+/// Decodes the chunks sent to it as one msgpack value, on a blocking thread as they come in.
+/// Hang up the sender once the body is complete.
+pub(crate) fn spawn_decoder<T: DeserializeOwned + Send + 'static>(
+) -> (mpsc::Sender<Bytes>, JoinHandle<Result<T, Error>>) {
+    let (sender, receiver) = mpsc::channel(CHUNKS_IN_FLIGHT);
+    let decoder = tokio::task::spawn_blocking(move || {
+        rmp_serde::from_read::<_, T>(ChannelReader::new(receiver))
+    });
+    (sender, decoder)
+}
+
+/// Body of a route taking either format, by the request's content type. Msgpack is
+/// streamed as [`StreamedMsgPack`], json is small enough for rocket's own guard.
+pub(crate) struct Payload<T>(pub(crate) T);
+
+impl<T> Deref for Payload<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum PayloadError {
+    #[error(transparent)]
+    MsgPack(Error),
+    #[error("{0}")]
+    Json(String),
+    #[error("Unsupported content type {0}")]
+    ContentType(String),
+}
+
+#[rocket::async_trait]
+impl<'r, T: DeserializeOwned + Send + 'static> FromData<'r> for Payload<T> {
+    type Error = PayloadError;
+
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        match req.content_type() {
+            Some(content_type) if content_type.is_json() => {
+                match Json::<T>::from_data(req, data).await {
+                    data::Outcome::Success(Json(value)) => data::Outcome::Success(Payload(value)),
+                    data::Outcome::Error((status, e)) => {
+                        data::Outcome::Error((status, PayloadError::Json(e.to_string())))
+                    }
+                    data::Outcome::Forward(forward) => data::Outcome::Forward(forward),
+                }
+            }
+            Some(content_type) if !content_type.is_msgpack() => data::Outcome::Error((
+                Status::UnsupportedMediaType,
+                PayloadError::ContentType(content_type.to_string()),
+            )),
+            // Msgpack is what the client sends, also when it doesn't say
+            _ => match StreamedMsgPack::<T>::from_data(req, data).await {
+                Ok(StreamedMsgPack(value)) => data::Outcome::Success(Payload(value)),
+                Err((status, e)) => data::Outcome::Error((status, PayloadError::MsgPack(e))),
+            },
+        }
+    }
+}
+
+/// Responds json, or msgpack to requests that prefer it in their `Accept` header.
+/// Json is the default, so curl and browsers can read every route.
+pub(crate) struct Negotiated<T>(pub(crate) T);
+
+impl<'r, T: Serialize> Responder<'r, 'static> for Negotiated<T> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        if prefers_msgpack(req) {
+            MsgPack(self.0).respond_to(req)
+        } else {
+            Json(self.0).respond_to(req)
+        }
+    }
+}
+
+fn prefers_msgpack(req: &Request<'_>) -> bool {
+    req.accept()
+        .map(|accept| accept.preferred().media_type() == &MediaType::MsgPack)
+        .unwrap_or(false)
+}
+
+/// Blocking reader over the chunks the async side receives
+struct ChannelReader {
+    receiver: mpsc::Receiver<Bytes>,
+    chunk: Bytes,
+}
+
+impl ChannelReader {
+    fn new(receiver: mpsc::Receiver<Bytes>) -> Self {
+        Self {
+            receiver,
+            chunk: Bytes::new(),
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.receiver.blocking_recv() {
+                Some(chunk) => self.chunk = chunk,
+                None => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len());
+        buf[..len].copy_from_slice(&self.chunk.split_to(len));
+        Ok(len)
+    }
+}
@@ -0,0 +1,318 @@
+//! What every route does, whatever transport the request came over. The rocket routes
+//! and the gRPC service only decode requests and encode answers around these.
+
+use crate::circuit::{
+    derive_server_key, evaluate_circuit, get_user_cell, with_thread_pool, PARAMETER,
+};
+use crate::dashboard::{Dashboard, FheStatus, RegisteredUser};
+use crate::error::{Error, PhaseError, ProtocolError, StorageError};
+use crate::fhe::set_parameter_set;
+use crate::types::{
+    CircuitOutput, DecryptionShare, DecryptionShareSubmission, EncryptedWord, GameStateEnc,
+    MutexServerStorage, Param, ServerState, SksSubmission, UserAction, UserId, UserStorage,
+};
+use tracing::{info, info_span, instrument};
+
+pub(crate) async fn get_param(ss: &MutexServerStorage) -> Param {
+    let ss = ss.lock().await;
+    Param {
+        seed: ss.seed,
+        config: ss.config.clone(),
+    }
+}
+
+#[instrument(skip(ss))]
+pub(crate) async fn register(ss: &MutexServerStorage, name: &str) -> Result<RegisteredUser, Error> {
+    let mut ss = ss.lock().await;
+    ss.ensure(ServerState::ReadyForJoining)?;
+    let user = ss.add_user(name);
+    info!("{name} just joined!");
+
+    let players = ss.config.players();
+    if ss.users.len() == players {
+        ss.transit(ServerState::ReadyForServerKeyShares);
+        info!("Got {players} players. Registration closed!");
+    }
+
+    Ok(user)
+}
+
+pub(crate) async fn get_dashboard(ss: &MutexServerStorage) -> Dashboard {
+    ss.lock().await.get_dashboard()
+}
+
+#[instrument(skip_all, fields(user_id = submission.user_id))]
+pub(crate) async fn submit_sks(
+    ss: &MutexServerStorage,
+    submission: SksSubmission<'static>,
+) -> Result<UserId, Error> {
+    // Verify before taking the lock, hashing a key share takes a while
+    submission.verify()?;
+
+    let mut ss = ss.lock().await;
+
+    ss.ensure(ServerState::ReadyForServerKeyShares)?;
+
+    let SksSubmission { user_id, sks, .. } = submission;
+
+    let user = ss.get_user(user_id)?;
+    info!("{} submited server key share.", user.name);
+    user.storage = UserStorage::Sks(Box::new(sks.into_owned()));
+
+    if ss.check_cipher_submission() {
+        ss.transit(ServerState::ReadyForSetupGame);
+        let server_key_shares = ss.get_sks()?;
+        set_parameter_set(PARAMETER);
+        // Long running, global variable change
+        derive_server_key(&server_key_shares);
+    }
+
+    Ok(user_id)
+}
+
+#[instrument(skip(ss, action))]
+pub(crate) async fn setup_game(
+    ss: &MutexServerStorage,
+    user_id: UserId,
+    action: UserAction<EncryptedWord>,
+) -> Result<UserId, Error> {
+    let mut ss = ss.lock().await;
+
+    ss.ensure(ServerState::ReadyForSetupGame)?;
+
+    let config = ss.config.clone();
+    let user = ss.get_user(user_id)?;
+    info!("{} requested action {}", user.name, action.to_string());
+    // Key switching is the costly part, turn away what this phase can't take first
+    if !matches!(
+        action,
+        UserAction::InitGame { .. } | UserAction::SetStartingCoord { .. }
+    ) {
+        return Err(PhaseError::new(ServerState::ReadyForSetupGame, &ss.state).into());
+    }
+    let action = action.unpack_checked(user_id, &config)?;
+
+    let result = match action {
+        UserAction::InitGame { initial_eggs } => {
+            match &mut ss.game_state {
+                Some(game_state) => game_state.eggs = initial_eggs,
+                None => {
+                    ss.game_state = Some(GameStateEnc {
+                        coords: vec![None; config.players()],
+                        eggs: initial_eggs,
+                    })
+                }
+            };
+            Ok(user_id)
+        }
+        UserAction::SetStartingCoord { starting_coord } => {
+            user.storage = UserStorage::StartingCoords;
+            match &mut ss.game_state {
+                Some(game_state) => game_state.coords[user_id] = Some(starting_coord),
+                None => {
+                    let mut coords = vec![None; config.players()];
+                    coords[user_id] = Some(starting_coord);
+                    ss.game_state = Some(GameStateEnc {
+                        coords,
+                        eggs: vec![],
+                    });
+                }
+            };
+            if ss.check_setup_game_complete() {
+                ss.transit(ServerState::ReadyForActions);
+                for user in ss.users.iter_mut() {
+                    user.storage = UserStorage::DecryptionShare(None);
+                }
+            }
+            Ok(user_id)
+        }
+        _ => Err(PhaseError::new(ServerState::ReadyForSetupGame, &ss.state).into()),
+    };
+
+    result
+}
+
+#[instrument(skip(ss, action))]
+pub(crate) async fn request_action(
+    ss: &MutexServerStorage,
+    user_id: UserId,
+    action: UserAction<EncryptedWord>,
+) -> Result<UserId, Error> {
+    let mut ss = ss.lock().await;
+
+    ss.ensure(ServerState::ReadyForActions)?;
+
+    let config = ss.config.clone();
+    let user = ss.get_user(user_id)?;
+    info!("{} requested action {}", user.name, action.to_string());
+    if matches!(
+        action,
+        UserAction::InitGame { .. } | UserAction::SetStartingCoord { .. } | UserAction::Done
+    ) {
+        return Err(PhaseError::new(ServerState::ReadyForActions, &ss.state).into());
+    }
+    let action = action.unpack_checked(user_id, &config)?;
+
+    let result = match action {
+        UserAction::MovePlayer { .. }
+        | UserAction::LayEgg { .. }
+        | UserAction::PickupEgg { .. }
+        | UserAction::GetCell { .. } => {
+            user.action_submitted = true;
+            ss.action_queue.push((user_id, action));
+            ss.transit(ServerState::ReadyForRunning);
+            Ok(user_id)
+        }
+        _ => Err(PhaseError::new(ServerState::ReadyForActions, &ss.state).into()),
+    };
+
+    result
+}
+
+#[instrument(skip(ss, action))]
+pub(crate) async fn done(
+    ss: &MutexServerStorage,
+    user_id: UserId,
+    action: UserAction<EncryptedWord>,
+) -> Result<UserId, Error> {
+    let mut ss = ss.lock().await;
+
+    ss.ensure(ServerState::CompletedFhe)?;
+
+    let user = ss.get_user(user_id)?;
+    info!("{} requested action {}", user.name, action.to_string());
+
+    // Only `Done` is taken here and it carries no word, so there's nothing to unpack
+    let result = match action {
+        UserAction::Done => {
+            user.ready_for_new_round = true;
+            Ok(user_id)
+        }
+        _ => Err(PhaseError::new(ServerState::CompletedFhe, &ss.state).into()),
+    };
+
+    if ss.check_ready_for_new_round() {
+        ss.round += 1;
+        if ss.config.max_rounds() == Some(ss.round) {
+            ss.transit(ServerState::GameOver);
+        } else {
+            ss.transit(ServerState::ReadyForActions);
+        }
+        for user in ss.users.iter_mut() {
+            user.ready_for_new_round = false;
+            user.action_submitted = false;
+            user.storage = UserStorage::DecryptionShare(None);
+        }
+    }
+
+    result
+}
+
+#[instrument(skip(ss))]
+pub(crate) async fn run(ss: &MutexServerStorage, user_id: UserId) -> Result<ServerState, Error> {
+    let s2 = ss.clone();
+    let mut ss = ss.lock().await;
+
+    match &ss.state {
+        ServerState::ReadyForRunning => {
+            let game_state = ss
+                .game_state
+                .clone()
+                .ok_or(StorageError::GameNotInitedYet)?;
+            // The final state is kept, so the queued actions are applied once
+            let uas = std::mem::take(&mut ss.action_queue);
+            // The output is the cell of who triggered the run
+            ss.output_user = Some(user_id);
+
+            // Outlives the request, its span is closed once the run completes
+            let span = info_span!("fhe_run", round = ss.round);
+            tokio::task::spawn_blocking(move || {
+                with_thread_pool(0, || {
+                    let _span = span.entered();
+                    info!("Begin FHE run");
+                    // Long running
+                    let final_game_state = evaluate_circuit(game_state, &uas);
+
+                    let cell = get_user_cell(&final_game_state, user_id);
+                    let mut ss = s2.blocking_lock();
+                    ss.game_state = Some(final_game_state);
+                    let cell = info_span!("pack_output").in_scope(|| CircuitOutput::new(cell));
+                    ss.circuit_output = Some(cell);
+
+                    ss.transit(ServerState::CompletedFhe);
+                    info!("FHE computation completed");
+                })
+            });
+            ss.transit(ServerState::RunningFhe);
+            Ok(ServerState::RunningFhe)
+        }
+        ServerState::RunningFhe => Ok(ServerState::RunningFhe),
+        ServerState::CompletedFhe => Ok(ServerState::CompletedFhe),
+        _ => Err(PhaseError::new(ServerState::ReadyForRunning, &ss.state).into()),
+    }
+}
+
+pub(crate) async fn get_fhe_status(ss: &MutexServerStorage) -> FheStatus {
+    ss.lock().await.get_fhe_status()
+}
+
+pub(crate) async fn get_fhe_output(ss: &MutexServerStorage) -> Result<CircuitOutput, Error> {
+    let ss = ss.lock().await;
+    ss.ensure(ServerState::CompletedFhe)?;
+    Ok(ss
+        .circuit_output
+        .clone()
+        .ok_or(StorageError::CellNotFound)?)
+}
+
+#[instrument(skip_all, fields(user_id = submission.user_id))]
+pub(crate) async fn submit_decryption_share(
+    ss: &MutexServerStorage,
+    submission: DecryptionShareSubmission,
+) -> Result<UserId, Error> {
+    submission.verify()?;
+
+    let user_id = submission.user_id;
+    let mut ss = ss.lock().await;
+    ss.ensure(ServerState::CompletedFhe)?;
+    let output_id = submission.decryption_share.0;
+    let share = &submission.decryption_share.1;
+    // A short share would only fail later, when other users decrypt with it
+    let expect = ss
+        .circuit_output
+        .as_ref()
+        .ok_or(ProtocolError::OutputNotReady)?
+        .n_bits();
+    if share.len() != expect {
+        return Err(ProtocolError::MalformedPayload {
+            payload: "decryption share".to_string(),
+            expect,
+            got: share.len(),
+        }
+        .into());
+    }
+    let decryption_share = ss
+        .get_user(user_id)?
+        .storage
+        .get_mut_decryption_share()
+        .ok_or(ProtocolError::OutputNotReady)?;
+    *decryption_share = Some(share.clone());
+
+    ss.decryption_shares
+        .insert((output_id, user_id), share.clone());
+    Ok(user_id)
+}
+
+pub(crate) async fn get_decryption_share(
+    ss: &MutexServerStorage,
+    output_id: usize,
+    user_id: UserId,
+) -> Result<DecryptionShare, Error> {
+    let ss = ss.lock().await;
+    ss.ensure(ServerState::CompletedFhe)?;
+    let decryption_share = ss
+        .decryption_shares
+        .get(&(output_id, user_id))
+        .ok_or(ProtocolError::DecryptionShareNotFound { user_id })?;
+    Ok(decryption_share.clone())
+}
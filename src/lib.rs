@@ -1,12 +1,46 @@
 mod circuit;
 mod client;
 mod compiled;
+mod config;
 mod dashboard;
+mod error;
+#[cfg(all(feature = "fhe", not(feature = "sim")))]
+mod fhe;
+mod game_client;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod guards;
+mod handlers;
+mod openapi;
+mod replay;
+#[cfg(feature = "keyring")]
+mod secrets;
 mod server;
+#[cfg(feature = "sim")]
+mod sim;
+mod telemetry;
 mod types;
 
-pub use client::WebClient;
-pub use server::{rocket, setup};
+#[cfg(feature = "sim")]
+use sim as fhe;
+
+#[cfg(not(any(feature = "fhe", feature = "sim")))]
+compile_error!("chickens needs an FHE backend, enable the `fhe` or the `sim` feature");
+
+pub use circuit::{
+    derive_server_key, evaluate_circuit, gen_server_key_share, get_user_cell, thread_pool,
+    with_thread_pool,
+};
+pub use client::{decryption_share_body, sks_submission_body, write_sks_submission, WebClient};
+pub use config::{EdgeBehavior, GameConfig, GameConfigBuilder};
+pub use dashboard::{Dashboard, FheStatus, RegisteredUser, UserStatus};
+pub use error::{CryptoError, Error, PhaseError, ProtocolError, StorageError};
+pub use fhe::{aggregate_server_key_shares, gen_client_key};
+pub use game_client::{Action, GameClient, Phase, Session};
+#[cfg(feature = "keyring")]
+pub use secrets::SecretStore;
+pub use server::{rocket, rocket_with, setup};
+pub use telemetry::{init_tracing, new_request_id, LogFormat, REQUEST_ID_HEADER};
 pub use types::{
     CircuitOutput, ClientKey, DecryptionSharesMap, ServerState, UserAction, UserId, *,
 };
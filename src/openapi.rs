@@ -0,0 +1,75 @@
+use crate::server;
+use utoipa::openapi::{ObjectBuilder, OpenApi as Doc, RefOr, Schema};
+use utoipa::{Modify, OpenApi, PartialSchema, ToSchema};
+
+const JSON: &str = "application/json";
+const MSGPACK: &str = "application/msgpack";
+
+/// OpenAPI description of the server, served at `/openapi.json` with a swagger UI at
+/// `/swagger-ui/`. Schemas are documented as json, msgpack has the same shape.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "chickens", description = "Server of the FHE chickens game"),
+    paths(
+        server::get_param,
+        server::register,
+        server::get_dashboard,
+        server::submit_sks,
+        server::setup_game,
+        server::request_action,
+        server::done,
+        server::run,
+        server::get_fhe_status,
+        server::get_fhe_output,
+        server::submit_decryption_share,
+        server::get_decryption_share,
+    ),
+    modifiers(&MsgPackContent)
+)]
+pub(crate) struct ApiDoc;
+
+/// Stands in for phantom-zone ciphertexts in schemas, they are opaque to anyone else
+pub(crate) struct Ciphertext;
+
+impl PartialSchema for Ciphertext {
+    fn schema() -> RefOr<Schema> {
+        ObjectBuilder::new()
+            .description(Some("phantom-zone ciphertext, serialized with serde"))
+            .into()
+    }
+}
+
+impl ToSchema for Ciphertext {}
+
+/// Lists msgpack next to json where the routes negotiate it, every body and successful
+/// response. Errors are json only.
+struct MsgPackContent;
+
+impl Modify for MsgPackContent {
+    fn modify(&self, openapi: &mut Doc) {
+        let operations = openapi
+            .paths
+            .paths
+            .values_mut()
+            .flat_map(|item| [item.get.as_mut(), item.post.as_mut()])
+            .flatten();
+        for operation in operations {
+            if let Some(body) = operation.request_body.as_mut() {
+                if let Some(content) = body.content.get(JSON).cloned() {
+                    body.content.insert(MSGPACK.to_string(), content);
+                }
+            }
+            for (status, response) in operation.responses.responses.iter_mut() {
+                let RefOr::T(response) = response else {
+                    continue;
+                };
+                if !status.starts_with('2') {
+                    continue;
+                }
+                if let Some(content) = response.content.get(JSON).cloned() {
+                    response.content.insert(MSGPACK.to_string(), content);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use crate::client::RawResponse;
+use crate::error::{Error, StorageError};
+use rocket::http::Method;
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// One request and the response it got. Request bodies aren't kept, they are encrypted
+/// with fresh randomness every run and the server is what a fixture stands in for.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Exchange {
+    method: String,
+    path: String,
+    response: RawResponse,
+}
+
+/// Appends exchanges to a fixture, one json line each, as they happen.
+/// A session that crashes still leaves the exchanges up to the crash.
+pub struct Recorder {
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl Recorder {
+    pub(crate) fn create(fixture: &Path) -> Result<Self, Error> {
+        if let Some(dir) = fixture.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let writer = BufWriter::new(File::create(fixture)?);
+        Ok(Self {
+            writer: Mutex::new(writer),
+        })
+    }
+
+    pub(crate) fn record(
+        &self,
+        method: Method,
+        path: &str,
+        response: &RawResponse,
+    ) -> Result<(), Error> {
+        let exchange = Exchange {
+            method: method.as_str().to_string(),
+            path: path.to_string(),
+            response: response.clone(),
+        };
+        let mut writer = self.writer.lock().unwrap();
+        serde_json::to_writer(&mut *writer, &exchange).map_err(std::io::Error::from)?;
+        writeln!(writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Serves the exchanges of a fixture in the order they were recorded.
+/// A request other than the next recorded one is an error, so a replay fails where the
+/// client strays from the recorded session.
+pub struct Replay {
+    fixture: PathBuf,
+    exchanges: Mutex<VecDeque<Exchange>>,
+}
+
+impl Replay {
+    pub(crate) fn load(fixture: &Path) -> Result<Self, Error> {
+        let exchanges = fs::read_to_string(fixture)?
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()
+            .map_err(|err| StorageError::Fixture {
+                fixture: fixture.display().to_string(),
+                reason: err.to_string(),
+            })?;
+        Ok(Self {
+            fixture: fixture.to_path_buf(),
+            exchanges: Mutex::new(exchanges),
+        })
+    }
+
+    pub(crate) fn fixture(&self) -> String {
+        self.fixture.display().to_string()
+    }
+
+    pub(crate) fn next(&self, method: Method, path: &str) -> Result<RawResponse, Error> {
+        let stray = |reason: String| StorageError::Fixture {
+            fixture: self.fixture(),
+            reason,
+        };
+        let exchange = self
+            .exchanges
+            .lock()
+            .unwrap()
+            .pop_front()
+            .ok_or_else(|| stray(format!("no exchange left for {} {}", method, path)))?;
+        if exchange.method != method.as_str() || exchange.path != path {
+            let reason = format!(
+                "expects {} {} next but got {} {}",
+                exchange.method, exchange.path, method, path
+            );
+            return Err(stray(reason).into());
+        }
+        Ok(exchange.response)
+    }
+}
@@ -0,0 +1,133 @@
+use crate::{Session, UserId};
+use anyhow::{anyhow, bail, Error};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::ChaCha20Poly1305;
+use keyring::Entry;
+use rand::{thread_rng, RngCore};
+use std::{fs, io, path::Path};
+
+const SERVICE: &str = "chickens";
+const REGISTRATION: &str = "registration";
+const PASSPHRASE: &str = "passphrase";
+
+/// blake3 key derivation context of the key encrypting sessions
+const SESSION_KEY_CONTEXT: &str = "chickens client session encryption key";
+const NONCE_LEN: usize = 12;
+
+/// Client secrets kept in the OS keyring.
+/// Entries are scoped to a player name on a given server.
+///
+/// The client key is too large for some keyrings. It is kept in a file instead, encrypted
+/// under a random passphrase the keyring keeps.
+pub struct SecretStore {
+    account: String,
+}
+
+impl SecretStore {
+    pub fn new(name: &str, url: &str) -> Self {
+        Self {
+            account: format!("{name}@{url}"),
+        }
+    }
+
+    fn entry(&self, label: &str) -> Result<Entry, Error> {
+        Ok(Entry::new(SERVICE, &format!("{}/{}", self.account, label))?)
+    }
+
+    fn load(&self, label: &str) -> Result<Option<String>, Error> {
+        match self.entry(label)?.get_password() {
+            Ok(secret) => Ok(Some(secret)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn store(&self, label: &str, secret: &str) -> Result<(), Error> {
+        Ok(self.entry(label)?.set_password(secret)?)
+    }
+
+    fn delete(&self, label: &str) -> Result<(), Error> {
+        match self.entry(label)?.delete_password() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// The user id the server handed out on registration, the token a registration is
+    /// resumed with
+    pub fn load_registration(&self) -> Result<Option<UserId>, Error> {
+        self.load(REGISTRATION)?
+            .map(|user_id| Ok(user_id.parse()?))
+            .transpose()
+    }
+
+    pub fn store_registration(&self, user_id: UserId) -> Result<(), Error> {
+        self.store(REGISTRATION, &user_id.to_string())
+    }
+
+    /// The session encrypted in `path`, if both the file and the passphrase are there
+    pub fn load_session(&self, path: &Path) -> Result<Option<Session>, Error> {
+        let Some(passphrase) = self.load(PASSPHRASE)? else {
+            return Ok(None);
+        };
+        let sealed = match fs::read(path) {
+            Ok(sealed) => sealed,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let session = open(&passphrase, &sealed)
+            .map_err(|err| anyhow!("Can't open the session in {}: {}", path.display(), err))?;
+        Ok(Some(rmp_serde::from_slice(&session)?))
+    }
+
+    /// Encrypt `session` into `path`, under the passphrase in the keyring. The passphrase
+    /// is generated the first time.
+    pub fn store_session(&self, path: &Path, session: &Session) -> Result<(), Error> {
+        let passphrase = match self.load(PASSPHRASE)? {
+            Some(passphrase) => passphrase,
+            None => {
+                let mut passphrase = [0u8; 32];
+                thread_rng().fill_bytes(&mut passphrase);
+                let passphrase = hex::encode(passphrase);
+                self.store(PASSPHRASE, &passphrase)?;
+                passphrase
+            }
+        };
+        let session = rmp_serde::to_vec_named(session)?;
+        fs::write(path, seal(&passphrase, &session)?)?;
+        Ok(())
+    }
+
+    /// Remove every entry stored for this player and server. A session file left behind
+    /// can't be opened anymore.
+    pub fn clear(&self) -> Result<(), Error> {
+        self.delete(REGISTRATION)?;
+        self.delete(PASSPHRASE)
+    }
+}
+
+fn cipher(passphrase: &str) -> ChaCha20Poly1305 {
+    let key = blake3::derive_key(SESSION_KEY_CONTEXT, passphrase.as_bytes());
+    ChaCha20Poly1305::new(&key.into())
+}
+
+/// Encrypt `plaintext` under `passphrase`, the random nonce goes first
+pub(crate) fn seal(passphrase: &str, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut nonce = [0u8; NONCE_LEN];
+    thread_rng().fill_bytes(&mut nonce);
+    let ciphertext = cipher(passphrase)
+        .encrypt(&nonce.into(), plaintext)
+        .map_err(|_| anyhow!("Encryption failed"))?;
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+/// Decrypt what [`seal`] encrypted under the same `passphrase`
+pub(crate) fn open(passphrase: &str, sealed: &[u8]) -> Result<Vec<u8>, Error> {
+    if sealed.len() < NONCE_LEN {
+        bail!("Too short");
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    cipher(passphrase)
+        .decrypt(nonce.into(), ciphertext)
+        .map_err(|_| anyhow!("Not encrypted under the passphrase in the keyring"))
+}
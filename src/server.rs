@@ -1,311 +1,252 @@
-use crate::circuit::{derive_server_key, evaluate_circuit, get_user_cell, PARAMETER};
-use crate::dashboard::{Dashboard, RegisteredUser};
+use crate::circuit::PARAMETER;
+use crate::config::{GameConfig, GameConfigBuilder};
+use crate::dashboard::{Dashboard, FheStatus, RegisteredUser};
+use crate::error::{ErrorBody, ErrorResponse, ProtocolError};
+use crate::fhe::{set_common_reference_seed, set_parameter_set};
+use crate::guards::{Negotiated, Payload};
+use crate::handlers;
+use crate::openapi::{ApiDoc, Ciphertext};
+use crate::telemetry::{self, RequestSpan};
 
 use crate::types::{
-    CircuitOutput, DecryptionShare, DecryptionShareSubmission, EncryptedWord, Error, ErrorResponse,
-    GameStateEnc, MutexServerStorage, Seed, ServerState, ServerStorage, SksSubmission, UserId,
-    UserStorage,
+    CircuitOutput, DecryptionShare, DecryptionShareSubmission, EncryptedWord, MutexServerStorage,
+    Param, Seed, ServerState, ServerStorage, SksSubmission, UserId,
 };
 use crate::UserAction;
-use phantom_zone::{set_common_reference_seed, set_parameter_set};
 use rand::{thread_rng, RngCore};
-use rocket::serde::json::Json;
-use rocket::serde::msgpack::MsgPack;
-use rocket::{get, post, routes};
-use rocket::{Build, Rocket, State};
+use rocket::http::Status;
+use rocket::{catch, catchers, get, post, routes};
+use rocket::{Build, Request, Rocket, State};
 use tokio::sync::Mutex;
+use tracing::Instrument;
+use utoipa::OpenApi;
+use utoipa_swagger_ui::SwaggerUi;
 
 use rocket::http::Method;
 use rocket_cors::{AllowedOrigins, CorsOptions};
 
+/// The common reference seed and the game played
+#[utoipa::path(get, path = "/param", responses((status = 200, body = Param)))]
 #[get("/param")]
-async fn get_param(ss: &State<MutexServerStorage>) -> Json<Seed> {
-    let ss = ss.lock().await;
-    Json(ss.seed)
+async fn get_param(ss: &State<MutexServerStorage>, span: RequestSpan) -> Negotiated<Param> {
+    Negotiated(handlers::get_param(ss).instrument(span.0).await)
 }
 
 /// A user registers a name and get an ID
-/// We support 4 players
+/// Registration closes once the players of the game joined
+#[utoipa::path(post, path = "/register",
+    request_body(content = String, description = "Name of the user", content_type = "text/plain"),
+    responses(
+        (status = 200, body = RegisteredUser),
+        (status = 409, description = "Registration is closed", body = ErrorBody),
+    ),
+)]
 #[post("/register", data = "<name>")]
 async fn register(
     name: &str,
     ss: &State<MutexServerStorage>,
-) -> Result<Json<RegisteredUser>, ErrorResponse> {
-    let mut ss = ss.lock().await;
-    ss.ensure(ServerState::ReadyForJoining)?;
-    let user = ss.add_user(name);
-    println!("{name} just joined!");
-
-    if ss.users.len() == 4 {
-        ss.transit(ServerState::ReadyForServerKeyShares);
-        println!("Got 4 players. Registration closed!");
-    }
-
-    Ok(Json(user))
+    span: RequestSpan,
+) -> Result<Negotiated<RegisteredUser>, ErrorResponse> {
+    Ok(Negotiated(
+        handlers::register(ss, name).instrument(span.0).await?,
+    ))
 }
 
+#[utoipa::path(get, path = "/dashboard", responses((status = 200, body = Dashboard)))]
 #[get("/dashboard")]
-async fn get_dashboard(ss: &State<MutexServerStorage>) -> Json<Dashboard> {
-    let dashboard = ss.lock().await.get_dashboard();
-    Json(dashboard)
+async fn get_dashboard(ss: &State<MutexServerStorage>, span: RequestSpan) -> Negotiated<Dashboard> {
+    Negotiated(handlers::get_dashboard(ss).instrument(span.0).await)
 }
 
 /// The user submits server key shares
-#[post("/submit_sks", data = "<submission>", format = "msgpack")]
+#[utoipa::path(post, path = "/submit_sks", request_body = SksSubmission, responses(
+    (status = 200, description = "Id of the user", body = usize),
+    (status = 400, description = "Malformed body or digest mismatch", body = ErrorBody),
+    (status = 404, description = "Unregistered user", body = ErrorBody),
+    (status = 409, description = "Not accepting key shares", body = ErrorBody),
+))]
+#[post("/submit_sks", data = "<submission>")]
 async fn submit_sks(
-    submission: MsgPack<SksSubmission>,
+    submission: Payload<SksSubmission<'static>>,
     ss: &State<MutexServerStorage>,
-) -> Result<Json<UserId>, ErrorResponse> {
-    let mut ss = ss.lock().await;
-
-    ss.ensure(ServerState::ReadyForServerKeyShares)?;
-
-    let SksSubmission { user_id, sks } = submission.0;
-
-    let user = ss.get_user(user_id)?;
-    println!("{} submited server key share.", user.name);
-    user.storage = UserStorage::Sks(Box::new(sks));
-
-    if ss.check_cipher_submission() {
-        ss.transit(ServerState::ReadyForSetupGame);
-        let server_key_shares = ss.get_sks()?;
-        set_parameter_set(PARAMETER);
-        // Long running, global variable change
-        derive_server_key(&server_key_shares);
-    }
-
-    Ok(Json(user_id))
+    span: RequestSpan,
+) -> Result<Negotiated<UserId>, ErrorResponse> {
+    Ok(Negotiated(
+        handlers::submit_sks(ss, submission.0)
+            .instrument(span.0)
+            .await?,
+    ))
 }
 
-#[post("/setup_game/<user_id>", data = "<action>", format = "msgpack")]
+#[utoipa::path(post, path = "/setup_game/{user_id}",
+    params(("user_id" = usize, Path, description = "Id the user got when registering")),
+    request_body(content = UserAction<Ciphertext>, description = "`InitGame` or `SetStartingCoord`"),
+    responses(
+        (status = 200, description = "Id of the user", body = usize),
+        (status = 400, description = "Malformed action", body = ErrorBody),
+        (status = 404, description = "Unregistered user", body = ErrorBody),
+        (status = 409, description = "Not setting up the game", body = ErrorBody),
+    ),
+)]
+#[post("/setup_game/<user_id>", data = "<action>")]
 async fn setup_game(
     user_id: UserId,
-    action: MsgPack<UserAction<EncryptedWord>>,
+    action: Payload<UserAction<EncryptedWord>>,
     ss: &State<MutexServerStorage>,
-) -> Result<Json<UserId>, ErrorResponse> {
-    let mut ss = ss.lock().await;
-
-    ss.ensure(ServerState::ReadyForSetupGame)?;
-
-    let user = ss.get_user(user_id)?;
-    println!("{} requested action {}", user.name, action.to_string());
-    let action = action.unpack(user_id);
-
-    let result = match action {
-        UserAction::InitGame { initial_eggs } => {
-            match &mut ss.game_state {
-                Some(game_state) => game_state.eggs = initial_eggs,
-                None => {
-                    ss.game_state = Some(GameStateEnc {
-                        coords: vec![None; 4],
-                        eggs: initial_eggs,
-                    })
-                }
-            };
-            Ok(Json(user_id))
-        }
-        UserAction::SetStartingCoord { starting_coord } => {
-            user.storage = UserStorage::StartingCoords;
-            match &mut ss.game_state {
-                Some(game_state) => game_state.coords[user_id] = Some(starting_coord),
-                None => {
-                    let mut coords = vec![None; 4];
-                    coords[user_id] = Some(starting_coord);
-                    ss.game_state = Some(GameStateEnc {
-                        coords,
-                        eggs: vec![],
-                    });
-                }
-            };
-            if ss.check_setup_game_complete() {
-                ss.transit(ServerState::ReadyForActions);
-                for user in ss.users.iter_mut() {
-                    user.storage = UserStorage::DecryptionShare(None);
-                }
-            }
-            Ok(Json(user_id))
-        }
-        _ => Err(Error::WrongServerState {
-            expect: ServerState::ReadyForSetupGame.to_string(),
-            got: ss.state.to_string(),
-        }
-        .into()),
-    };
-
-    result
+    span: RequestSpan,
+) -> Result<Negotiated<UserId>, ErrorResponse> {
+    let user_id = handlers::setup_game(ss, user_id, action.0)
+        .instrument(span.0)
+        .await?;
+    Ok(Negotiated(user_id))
 }
 
-#[post("/request_action/<user_id>", data = "<action>", format = "msgpack")]
+#[utoipa::path(post, path = "/request_action/{user_id}",
+    params(("user_id" = usize, Path, description = "Id the user got when registering")),
+    request_body(content = UserAction<Ciphertext>, description = "Queued for the next run"),
+    responses(
+        (status = 200, description = "Id of the user", body = usize),
+        (status = 400, description = "Malformed action", body = ErrorBody),
+        (status = 404, description = "Unregistered user", body = ErrorBody),
+        (status = 409, description = "Not taking actions", body = ErrorBody),
+    ),
+)]
+#[post("/request_action/<user_id>", data = "<action>")]
 async fn request_action(
     user_id: UserId,
-    action: MsgPack<UserAction<EncryptedWord>>,
+    action: Payload<UserAction<EncryptedWord>>,
     ss: &State<MutexServerStorage>,
-) -> Result<Json<UserId>, ErrorResponse> {
-    let mut ss = ss.lock().await;
-
-    ss.ensure(ServerState::ReadyForActions)?;
-
-    let user = ss.get_user(user_id)?;
-    println!("{} requested action {}", user.name, action.to_string());
-    let action = action.unpack(user_id);
-
-    let result = match action {
-        UserAction::MovePlayer { .. }
-        | UserAction::LayEgg { .. }
-        | UserAction::PickupEgg { .. }
-        | UserAction::GetCell { .. } => {
-            ss.action_queue.push((user_id, action));
-            ss.transit(ServerState::ReadyForRunning);
-            Ok(Json(user_id))
-        }
-        _ => Err(Error::WrongServerState {
-            expect: ServerState::ReadyForActions.to_string(),
-            got: ss.state.to_string(),
-        }
-        .into()),
-    };
-
-    result
+    span: RequestSpan,
+) -> Result<Negotiated<UserId>, ErrorResponse> {
+    let user_id = handlers::request_action(ss, user_id, action.0)
+        .instrument(span.0)
+        .await?;
+    Ok(Negotiated(user_id))
 }
 
-#[post("/done/<user_id>", data = "<action>", format = "msgpack")]
+#[utoipa::path(post, path = "/done/{user_id}",
+    params(("user_id" = usize, Path, description = "Id the user got when registering")),
+    request_body(content = UserAction<Ciphertext>, description = "`Done`"),
+    responses(
+        (status = 200, description = "Id of the user", body = usize),
+        (status = 404, description = "Unregistered user", body = ErrorBody),
+        (status = 409, description = "The run isn't completed", body = ErrorBody),
+    ),
+)]
+#[post("/done/<user_id>", data = "<action>")]
 async fn done(
     user_id: UserId,
-    action: MsgPack<UserAction<EncryptedWord>>,
+    action: Payload<UserAction<EncryptedWord>>,
     ss: &State<MutexServerStorage>,
-) -> Result<Json<UserId>, ErrorResponse> {
-    let mut ss = ss.lock().await;
-
-    ss.ensure(ServerState::CompletedFhe)?;
-
-    let user = ss.get_user(user_id)?;
-    println!("{} requested action {}", user.name, action.to_string());
-    let action = action.unpack(user_id);
-
-    let result = match action {
-        UserAction::Done => {
-            user.ready_for_new_round = true;
-            Ok(Json(user_id))
-        }
-        _ => Err(Error::WrongServerState {
-            expect: ServerState::CompletedFhe.to_string(),
-            got: ss.state.to_string(),
-        }
-        .into()),
-    };
-
-    if ss.check_ready_for_new_round() {
-        ss.round += 1;
-        ss.transit(ServerState::ReadyForActions);
-        for user in ss.users.iter_mut() {
-            user.ready_for_new_round = false;
-            user.storage = UserStorage::DecryptionShare(None);
-        }
-    }
-
-    result
+    span: RequestSpan,
+) -> Result<Negotiated<UserId>, ErrorResponse> {
+    Ok(Negotiated(
+        handlers::done(ss, user_id, action.0)
+            .instrument(span.0)
+            .await?,
+    ))
 }
 
+#[utoipa::path(post, path = "/run/{user_id}",
+    params(("user_id" = usize, Path, description = "Id the user got when registering")),
+    responses(
+        (status = 200, description = "State of the run", body = ServerState),
+        (status = 409, description = "Not ready to run", body = ErrorBody),
+    ),
+)]
 #[post("/run/<user_id>")]
 async fn run(
     user_id: UserId,
     ss: &State<MutexServerStorage>,
-) -> Result<Json<ServerState>, ErrorResponse> {
-    let s2 = (*ss).clone();
-    let mut ss = ss.lock().await;
-
-    match &ss.state {
-        ServerState::ReadyForRunning => {
-            let game_state = ss.game_state.clone().ok_or(Error::GameNotInitedYet)?;
-            let uas = ss.action_queue.clone();
-
-            tokio::task::spawn_blocking(move || {
-                rayon::ThreadPoolBuilder::new()
-                    .build_scoped(
-                        // Initialize thread-local storage parameters
-                        |thread| {
-                            set_parameter_set(PARAMETER);
-                            thread.run()
-                        },
-                        // Run parallel code under this pool
-                        |pool| {
-                            pool.install(|| {
-                                println!("Begin FHE run");
-                                // Long running
-                                let final_game_state = evaluate_circuit(game_state, &uas);
-
-                                let cell = get_user_cell(&final_game_state, user_id);
-                                let mut ss = s2.blocking_lock();
-                                ss.game_state = Some(final_game_state);
-                                let cell = CircuitOutput::new(cell);
-                                ss.circuit_output = Some(cell);
+    span: RequestSpan,
+) -> Result<Negotiated<ServerState>, ErrorResponse> {
+    Ok(Negotiated(
+        handlers::run(ss, user_id).instrument(span.0).await?,
+    ))
+}
 
-                                ss.transit(ServerState::CompletedFhe);
-                                println!("FHE computation completed");
-                            })
-                        },
-                    )
-                    .unwrap();
-            });
-            ss.transit(ServerState::RunningFhe);
-            Ok(Json(ServerState::RunningFhe))
-        }
-        ServerState::RunningFhe => Ok(Json(ServerState::RunningFhe)),
-        ServerState::CompletedFhe => Ok(Json(ServerState::CompletedFhe)),
-        _ => Err(Error::WrongServerState {
-            expect: ServerState::ReadyForRunning.to_string(),
-            got: ss.state.to_string(),
-        }
-        .into()),
-    }
+/// State of the ongoing FHE run, and whose cell it outputs
+#[utoipa::path(get, path = "/fhe_status", responses((status = 200, body = FheStatus)))]
+#[get("/fhe_status")]
+async fn get_fhe_status(
+    ss: &State<MutexServerStorage>,
+    span: RequestSpan,
+) -> Negotiated<FheStatus> {
+    Negotiated(handlers::get_fhe_status(ss).instrument(span.0).await)
 }
 
+#[utoipa::path(get, path = "/fhe_output", responses(
+    (status = 200, body = CircuitOutput),
+    (status = 409, description = "The run isn't completed", body = ErrorBody),
+))]
 #[get("/fhe_output")]
 async fn get_fhe_output(
     ss: &State<MutexServerStorage>,
-) -> Result<Json<CircuitOutput>, ErrorResponse> {
-    let ss = ss.lock().await;
-    ss.ensure(ServerState::CompletedFhe)?;
-    let cell = ss.circuit_output.clone().ok_or(Error::CellNotFound)?;
-    Ok(Json(cell))
+    span: RequestSpan,
+) -> Result<Negotiated<CircuitOutput>, ErrorResponse> {
+    Ok(Negotiated(
+        handlers::get_fhe_output(ss).instrument(span.0).await?,
+    ))
 }
 
 /// The user submits the ciphertext
-#[post("/submit_decryption_share", data = "<submission>", format = "msgpack")]
+#[utoipa::path(post, path = "/submit_decryption_share",
+    request_body = DecryptionShareSubmission,
+    responses(
+        (status = 200, description = "Id of the user", body = usize),
+        (status = 400, description = "Malformed share or digest mismatch", body = ErrorBody),
+        (status = 404, description = "Unregistered user or no output yet", body = ErrorBody),
+        (status = 409, description = "The run isn't completed", body = ErrorBody),
+    ),
+)]
+#[post("/submit_decryption_share", data = "<submission>")]
 async fn submit_decryption_share(
-    submission: MsgPack<DecryptionShareSubmission>,
+    submission: Payload<DecryptionShareSubmission>,
     ss: &State<MutexServerStorage>,
-) -> Result<Json<UserId>, ErrorResponse> {
-    let user_id = submission.user_id;
-    let mut ss = ss.lock().await;
-    ss.ensure(ServerState::CompletedFhe)?;
-    let decryption_share = ss
-        .get_user(user_id)?
-        .storage
-        .get_mut_decryption_share()
-        .ok_or(Error::OutputNotReady)?;
-    let output_id = submission.decryption_share.0;
-    let share = &submission.decryption_share.1;
-    *decryption_share = Some(share.clone());
-
-    ss.decryption_shares
-        .insert((output_id, user_id), share.clone());
-    Ok(Json(user_id))
+    span: RequestSpan,
+) -> Result<Negotiated<UserId>, ErrorResponse> {
+    let user_id = handlers::submit_decryption_share(ss, submission.0)
+        .instrument(span.0)
+        .await?;
+    Ok(Negotiated(user_id))
 }
 
+#[utoipa::path(get, path = "/decryption_share/{output_id}/{user_id}",
+    params(
+        ("output_id" = usize, Path, description = "Output of the run"),
+        ("user_id" = usize, Path, description = "Id the user got when registering"),
+    ),
+    responses(
+        (status = 200, body = Vec<u64>),
+        (status = 404, description = "Not submitted yet", body = ErrorBody),
+        (status = 409, description = "The run isn't completed", body = ErrorBody),
+    ),
+)]
 #[get("/decryption_share/<output_id>/<user_id>")]
 async fn get_decryption_share(
     output_id: usize,
     user_id: UserId,
     ss: &State<MutexServerStorage>,
-) -> Result<Json<DecryptionShare>, ErrorResponse> {
-    let mut ss: tokio::sync::MutexGuard<ServerStorage> = ss.lock().await;
-    ss.ensure(ServerState::CompletedFhe)?;
-    let decryption_share = ss
-        .decryption_shares
-        .get(&(output_id, user_id))
-        .ok_or(Error::OutputNotReady)?;
-    Ok(Json(decryption_share.clone()))
+    span: RequestSpan,
+) -> Result<Negotiated<DecryptionShare>, ErrorResponse> {
+    let share = handlers::get_decryption_share(ss, output_id, user_id)
+        .instrument(span.0)
+        .await?;
+    Ok(Negotiated(share))
+}
+
+/// Requests turned down before getting to a route, bodies that don't decode or unknown
+/// paths, get the same kind of error body as the routes answer
+#[catch(default)]
+fn reject(status: Status, _: &Request) -> ErrorResponse {
+    let reason = status.reason_lossy().to_string();
+    ErrorResponse(
+        ProtocolError::Rejected {
+            status: status.code,
+            reason,
+        }
+        .into(),
+    )
 }
 
 pub fn setup(seed: &Seed) {
@@ -313,7 +254,19 @@ pub fn setup(seed: &Seed) {
     set_common_reference_seed(*seed);
 }
 
+/// The server, playing the game of the `game` key of its configuration
 pub fn rocket() -> Rocket<Build> {
+    let config = match rocket::Config::figment().extract_inner::<GameConfigBuilder>("game") {
+        Ok(builder) => builder.build().expect("Unsupported game config"),
+        // No `game` key at all, anything else in it is a mistake to stop on
+        Err(err) if err.missing() => GameConfig::default(),
+        Err(err) => panic!("Invalid game config: {err}"),
+    };
+    rocket_with(config)
+}
+
+/// Same as [`rocket`], playing `config` whatever the configuration says
+pub fn rocket_with(config: GameConfig) -> Rocket<Build> {
     let mut seed = [0u8; 32];
     thread_rng().fill_bytes(&mut seed);
     setup(&seed);
@@ -328,11 +281,17 @@ pub fn rocket() -> Rocket<Build> {
         )
         .allow_credentials(true);
 
-    rocket::build()
+    let rocket = rocket::build();
+    let mut ss = ServerStorage::new(seed);
+    ss.config = config;
+
+    let rocket = rocket.attach(telemetry::fairing());
+    #[cfg(feature = "grpc")]
+    let rocket = rocket.attach(crate::grpc::fairing());
+
+    rocket
         .attach(cors.to_cors().unwrap())
-        .manage(MutexServerStorage::new(Mutex::new(ServerStorage::new(
-            seed,
-        ))))
+        .manage(MutexServerStorage::new(Mutex::new(ss)))
         .mount(
             "/",
             routes![
@@ -344,9 +303,15 @@ pub fn rocket() -> Rocket<Build> {
                 request_action,
                 done,
                 run,
+                get_fhe_status,
                 get_fhe_output,
                 submit_decryption_share,
                 get_decryption_share,
             ],
         )
+        .register("/", catchers![reject])
+        .mount(
+            "/",
+            SwaggerUi::new("/swagger-ui/<_..>").url("/openapi.json", ApiDoc::openapi()),
+        )
 }
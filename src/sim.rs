@@ -0,0 +1,140 @@
+//! Plaintext stand-ins for [`crate::fhe`], enabled by the `sim` feature.
+//!
+//! "Ciphertexts" carry their plaintext, keys are empty and decryption shares are zeros.
+//! The compiled circuits run gate by gate as usual, on plain booleans, so a whole round
+//! takes milliseconds. Nothing is private in this mode, it is for iterating on the game.
+
+use crate::types::UserId;
+use serde::{Deserialize, Serialize};
+use std::ops::{BitAnd, BitOr, BitXor, Not};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParameterSelector {
+    NonInteractiveLTE4Party,
+}
+
+pub fn set_parameter_set(_parameter: ParameterSelector) {}
+
+pub fn get_active_parameter_set() -> ParameterSelector {
+    ParameterSelector::NonInteractiveLTE4Party
+}
+
+pub fn set_common_reference_seed(_seed: [u8; 32]) {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct FheBool(bool);
+
+impl BitAnd for &FheBool {
+    type Output = FheBool;
+
+    fn bitand(self, rhs: Self) -> FheBool {
+        FheBool(self.0 & rhs.0)
+    }
+}
+
+impl BitOr for &FheBool {
+    type Output = FheBool;
+
+    fn bitor(self, rhs: Self) -> FheBool {
+        FheBool(self.0 | rhs.0)
+    }
+}
+
+impl BitXor for &FheBool {
+    type Output = FheBool;
+
+    fn bitxor(self, rhs: Self) -> FheBool {
+        FheBool(self.0 ^ rhs.0)
+    }
+}
+
+impl Not for &FheBool {
+    type Output = FheBool;
+
+    fn not(self) -> FheBool {
+        FheBool(!self.0)
+    }
+}
+
+impl FheBool {
+    pub fn nand(&self, rhs: &FheBool) -> FheBool {
+        FheBool(!(self.0 & rhs.0))
+    }
+
+    pub fn nor(&self, rhs: &FheBool) -> FheBool {
+        FheBool(!(self.0 | rhs.0))
+    }
+
+    pub fn xnor(&self, rhs: &FheBool) -> FheBool {
+        FheBool(!(self.0 ^ rhs.0))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ClientKey;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ServerKeyShare;
+
+#[derive(Clone, Debug)]
+pub struct ServerKey;
+
+impl ServerKey {
+    pub fn set_server_key(&self) {}
+}
+
+pub type EncryptedWord = Vec<bool>;
+
+pub fn gen_client_key() -> ClientKey {
+    ClientKey
+}
+
+pub fn gen_server_key_share(
+    _user_id: UserId,
+    _total_users: usize,
+    _client_key: &ClientKey,
+) -> ServerKeyShare {
+    ServerKeyShare
+}
+
+pub fn aggregate_server_key_shares(_shares: &[ServerKeyShare]) -> ServerKey {
+    ServerKey
+}
+
+pub fn unpack_word(word: &EncryptedWord, _user_id: UserId) -> Vec<FheBool> {
+    word.iter().copied().map(FheBool).collect()
+}
+
+/// Same shape as phantom-zone's trait, so callers don't change with the backend
+pub trait Encryptor<M: ?Sized, C> {
+    fn encrypt(&self, m: &M) -> C;
+}
+
+impl Encryptor<[bool], EncryptedWord> for ClientKey {
+    fn encrypt(&self, m: &[bool]) -> EncryptedWord {
+        m.to_vec()
+    }
+}
+
+/// Same shape as phantom-zone's trait, so callers don't change with the backend
+pub trait MultiPartyDecryptor<M, C> {
+    type DecryptionShare;
+
+    fn gen_decryption_share(&self, c: &C) -> Self::DecryptionShare;
+    fn aggregate_decryption_shares(&self, c: &C, shares: &[Self::DecryptionShare]) -> M;
+}
+
+impl MultiPartyDecryptor<bool, FheBool> for ClientKey {
+    type DecryptionShare = u64;
+
+    fn gen_decryption_share(&self, _c: &FheBool) -> u64 {
+        0
+    }
+
+    fn aggregate_decryption_shares(&self, c: &FheBool, _shares: &[u64]) -> bool {
+        c.0
+    }
+}
@@ -0,0 +1,106 @@
+//! Logs of the server, as tracing spans and events. Every request gets an ID, the one in
+//! its `X-Request-Id` header or a new one. Its span carries the ID, the answer sends it
+//! back in the header and in error bodies.
+
+use clap::ValueEnum;
+use rand::{thread_rng, Rng};
+use rocket::fairing::AdHoc;
+use rocket::http::Header;
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+use std::convert::Infallible;
+use std::fmt::Display;
+use tracing::{info, info_span, Span};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+/// Longest request ID taken from a client, longer ones are replaced
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Lines for humans, rocket logs its own way
+    #[default]
+    Text,
+    /// A json object per line, rocket's logs too
+    Json,
+}
+
+/// Log on stdout at the level `RUST_LOG` asks for, info if unset. A span logs how long it
+/// took when it closes, a stuck FHE run shows as the phase that didn't.
+pub fn init_tracing(format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let registry = tracing_subscriber::registry().with(filter);
+    match format {
+        LogFormat::Text => {
+            let layer = tracing_subscriber::fmt::layer().with_span_events(FmtSpan::CLOSE);
+            tracing::subscriber::set_global_default(registry.with(layer))
+                .expect("tracing is initialized once");
+        }
+        // Also takes over the `log` records of rocket, so every line is json. Lines list the
+        // spans they are in, from the outermost
+        LogFormat::Json => {
+            let layer = tracing_subscriber::fmt::layer()
+                .json()
+                .with_span_events(FmtSpan::CLOSE);
+            registry.with(layer).init()
+        }
+    }
+}
+
+/// A new ID for a request, for clients to send in [`REQUEST_ID_HEADER`]
+pub fn new_request_id() -> String {
+    format!("{:016x}", thread_rng().gen::<u64>())
+}
+
+/// Correlation ID of a request
+#[derive(Debug, Clone)]
+pub(crate) struct RequestId(pub(crate) String);
+
+impl RequestId {
+    /// The ID of `req`, the same every time it is asked for
+    pub(crate) fn of<'r>(req: &'r Request<'_>) -> &'r Self {
+        req.local_cache(|| {
+            let id = req.headers().get_one(REQUEST_ID_HEADER);
+            match id.filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN) {
+                Some(id) => RequestId(id.to_string()),
+                None => RequestId(new_request_id()),
+            }
+        })
+    }
+}
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Span of a request, routes run their handler in it
+pub(crate) struct RequestSpan(pub(crate) Span);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestSpan {
+    type Error = Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let id = RequestId::of(req);
+        let span = info_span!("request", id = %id, method = %req.method(), uri = %req.uri());
+        Outcome::Success(RequestSpan(span))
+    }
+}
+
+/// Sends the ID of every request back and logs the answer
+pub(crate) fn fairing() -> AdHoc {
+    AdHoc::on_response("Request IDs", |req, res| {
+        Box::pin(async move {
+            let id = RequestId::of(req);
+            res.set_header(Header::new(REQUEST_ID_HEADER, id.0.clone()));
+            let status = res.status().code;
+            info!(request_id = %id, method = %req.method(), uri = %req.uri(), status, "answered");
+        })
+    })
+}
@@ -1,298 +1,743 @@
 use crate::circuit::*;
+use crate::client::{RequestBody, ResponseBody};
+use crate::fhe::{gen_client_key, gen_server_key_share, set_parameter_set};
 use crate::types::*;
 use crate::*;
 use anyhow::Error;
-use futures::future::join_all;
 use itertools::Itertools;
-use phantom_zone::MultiPartyDecryptor;
-use phantom_zone::{gen_client_key, gen_server_key_share, set_parameter_set};
-use rayon::iter::{IntoParallelRefMutIterator, ParallelIterator};
+use proptest::prelude::*;
 use rocket::{
-    serde::{msgpack, Deserialize, Serialize},
+    data::Limits,
+    http::{ContentType, Header, MediaType, Method, Status},
+    serde::msgpack,
     Build, Rocket,
 };
-use std::{collections::HashMap, time::Duration};
-use tokio::time::sleep;
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-// We're not sending the User struct in rockets. This macro is here just for Serde reasons
-#[serde(crate = "rocket::serde")]
-struct User {
-    name: String,
-    // step 0: get seed
-    seed: Option<Seed>,
-    // step 0.5: gen client key
-    ck: Option<ClientKey>,
-    // step 1: get userID
-    id: Option<UserId>,
-    total_users: Option<usize>,
-    // step 2: assign starting coordinates
-    starting_coords: Option<(u8, u8)>,
-    // step 3: gen key and cipher
-    server_key: Option<ServerKeyShare>,
-    // step 4: get FHE output
-    fhe_out: Option<CircuitOutput>,
-    // step 5: derive decryption shares
-    decryption_shares: DecryptionSharesMap,
-}
+use std::time::Duration;
+use tokio::{runtime::Runtime, time::sleep};
+
+/// Routes taking a msgpack body
+const MSGPACK_ROUTES: [&str; 5] = [
+    "/submit_sks",
+    "/setup_game/0",
+    "/request_action/0",
+    "/done/0",
+    "/submit_decryption_share",
+];
 
-impl User {
-    fn new(name: &str) -> Self {
-        Self {
-            name: name.to_string(),
-            seed: None,
-            ck: None,
-            id: None,
-            total_users: None,
-            starting_coords: None,
-            server_key: None,
-            fhe_out: None,
-            decryption_shares: HashMap::new(),
-        }
+impl WebClient {
+    pub(crate) async fn new_test(rocket: Rocket<Build>) -> Result<Self, Error> {
+        let client = rocket::local::asynchronous::Client::tracked(rocket).await?;
+        Ok(Self::Test(Box::new(client)))
     }
 
-    fn assign_seed(&mut self, seed: Seed) -> &mut Self {
-        self.seed = Some(seed);
-        self
+    /// Post `body` as msgpack and only look at the status, for fuzzing
+    async fn post_raw(&self, path: &str, body: Vec<u8>) -> Status {
+        let body = RequestBody::MsgPack(body);
+        let response = self.send(Method::Post, path, &MediaType::JSON, body).await;
+        Status::from_code(response.unwrap().status).unwrap()
     }
+}
 
-    fn gen_client_key(&mut self) -> &mut Self {
-        self.ck = Some(gen_client_key());
-        self
-    }
+/// An in-process player
+struct Player {
+    id: UserId,
+    ck: ClientKey,
+}
 
-    fn set_id(&mut self, id: usize) -> &mut Self {
-        self.id = Some(id);
-        self
-    }
+/// Test harness, drives in-process players through whole games against a test server
+struct Game {
+    client: WebClient,
+    players: Vec<Player>,
+    round: usize,
+}
 
-    fn set_total_users(&mut self, total_users: usize) -> &mut Self {
-        self.total_users = Some(total_users);
-        self
+impl Game {
+    /// Register `total_users` players and submit their server key shares
+    async fn start(total_users: usize) -> Result<Self, Error> {
+        let client = WebClient::new_test(rocket()).await?;
+        Self::start_with(client, total_users).await
     }
 
-    fn assign_starting_coords(&mut self, coords: &(u8, u8)) -> &mut Self {
-        self.starting_coords = Some(coords.clone());
-        self
-    }
+    /// Same as [`Game::start`], against any client
+    async fn start_with(client: WebClient, total_users: usize) -> Result<Self, Error> {
+        let mut players = vec![];
+        for i in 0..total_users {
+            client.get_seed().await?;
+            let ck = gen_client_key();
+            let reg = client.register(&format!("User {i}")).await?;
+            players.push(Player { id: reg.id, ck });
+        }
+        assert_eq!(client.get_dashboard().await?.get_names().len(), total_users);
+
+        for player in players.iter() {
+            set_parameter_set(PARAMETER);
+            let sks = time!(
+                || gen_server_key_share(player.id, total_users, &player.ck),
+                format!("User {} Gen server key share", player.id)
+            );
+            client.submit_sks(player.id, &sks).await?;
+        }
+        assert!(client.get_dashboard().await?.is_submit_sks_complete());
 
-    fn gen_server_key_share(&mut self) -> &mut Self {
-        let server_key = gen_server_key_share(
-            self.id.unwrap(),
-            self.total_users.unwrap(),
-            self.ck.as_ref().unwrap(),
-        );
-        self.server_key = Some(server_key);
-        self
+        Ok(Self {
+            client,
+            players,
+            round: 0,
+        })
     }
 
-    fn set_fhe_out(&mut self, fhe_out: CircuitOutput) -> &mut Self {
-        self.fhe_out = Some(fhe_out);
-        self
+    /// Player 0 lays out the eggs, then every player sets their starting coordinates
+    async fn setup(&self, initial_eggs: &[bool], coords: &[(u8, u8)]) -> Result<(), Error> {
+        let ck = &self.players[0].ck;
+        self.client.init_game(ck, 0, initial_eggs).await?;
+        for (player, coord) in self.players.iter().zip_eq(coords) {
+            self.client
+                .set_starting_coords(&player.ck, player.id, coord)
+                .await?;
+        }
+        Ok(())
     }
 
-    // Populate decryption_shares with my shares
-    // fn gen_decryption_shares(&mut self) -> &mut Self {
-    //     let ck = self.ck.as_ref().expect("already exists");
-    //     let fhe_out = self.fhe_out.as_ref().expect("exists");
-    //     let my_id = self.id.expect("exists");
-
-    //     let my_decryption_shares = fhe_out.gen_decryption_shares(ck);
-    //     for (out_id, share) in my_decryption_shares.iter() {
-    //         self.decryption_shares
-    //             .insert((*out_id, my_id), share.to_vec());
-    //     }
-    //     self
-    // }
-
-    // fn get_my_shares(&self) -> Vec<AnnotatedDecryptionShare> {
-    //     let total_users = self.total_users.expect("exist");
-    //     let my_id = self.id.expect("exists");
-    //     (0..total_users)
-    //         .filter_map(|output_id| {
-    //             if output_id == my_id {
-    //                 return None;
-    //             };
-
-    //             let share = self
-    //                 .decryption_shares
-    //                 .get(&(output_id, my_id))
-    //                 .expect("exists")
-    //                 .to_owned();
-    //             Some((output_id, share))
-    //         })
-    //         .collect_vec()
-    // }
-
-    // fn decrypt_everything(&self) -> Vec<Vec<bool>> {
-    //     let total_users = self.total_users.expect("exist");
-    //     let ck = self.ck.as_ref().expect("already exists");
-    //     let co = self.fhe_out.as_ref().expect("exists");
-
-    //     let dss = (0..co.n())
-    //         .map(|output_id| {
-    //             (0..total_users)
-    //                 .map(|user_id| {
-    //                     self.decryption_shares
-    //                         .get(&(output_id, user_id))
-    //                         .expect("exists")
-    //                         .to_owned()
-    //                 })
-    //                 .collect_vec()
-    //         })
-    //         .collect_vec();
-    //     co.decrypt(ck, &dss)
-    // }
-}
+    /// Play a round: `user_id` takes `turn`, the server runs FHE, every player submits
+    /// a decryption share and `user_id` decrypts their cell.
+    async fn play(&mut self, user_id: UserId, turn: Action) -> Result<Vec<bool>, Error> {
+        let client = &self.client;
+        let player = &self.players[user_id];
+        match turn {
+            Action::Move(direction) => client.move_player(&player.ck, user_id, direction).await?,
+            Action::LayEgg => client.lay_egg(user_id).await?,
+            Action::PickupEgg => client.pickup_egg(user_id).await?,
+            Action::GetCell => client.get_cell(user_id).await?,
+        };
+
+        while client.trigger_fhe_run(user_id).await? != ServerState::CompletedFhe {
+            sleep(Duration::from_millis(100)).await
+        }
 
-impl WebClient {
-    pub(crate) async fn new_test(rocket: Rocket<Build>) -> Result<Self, Error> {
-        let client = rocket::local::asynchronous::Client::tracked(rocket).await?;
-        Ok(Self::Test(Box::new(client)))
+        let fhe_out = client.get_fhe_output().await?;
+        for player in self.players.iter() {
+            let share = fhe_out.gen_decryption_share(&player.ck);
+            client
+                .submit_decryption_share(player.id, &(self.round, share))
+                .await?;
+        }
+        let mut dss = vec![];
+        for player in self.players.iter() {
+            dss.push(client.get_decryption_share(self.round, player.id).await?);
+        }
+        let cell = fhe_out.decrypt(&player.ck, &dss);
+
+        let dashboard = client.get_dashboard().await?;
+        assert_eq!(dashboard.round(), self.round);
+        for user in dashboard.users() {
+            assert_eq!(user.action_submitted, user.id == user_id);
+            assert!(user.sks_received && user.share_submitted);
+        }
+
+        for player in self.players.iter() {
+            client.done(player.id).await?;
+        }
+        self.round += 1;
+        Ok(cell)
     }
 }
 
-async fn run_flow_with_n_users(total_users: usize) -> Result<(), Error> {
-    let client = WebClient::new_test(rocket()).await.unwrap();
+#[rocket::async_test]
+async fn full_flow() {
+    // Need to fix the global variable thing to allow multiple flow run
+    let mut game = Game::start(4).await.unwrap();
+    game.setup(&[false; BOARD_SIZE], &[(0, 0), (1, 1), (2, 2), (3, 3)])
+        .await
+        .unwrap();
 
-    let mut users = (0..total_users)
-        .map(|i| User::new(&format!("User {i}")))
-        .collect_vec();
+    // A cell is who of the 4 players stands on it, then whether it has an egg
+    let cell = game.play(0, Action::LayEgg).await.unwrap();
+    assert_eq!(cell, [true, false, false, false, true]);
 
-    println!("acquire seeds");
+    let cell = game.play(1, Action::Move(Direction::Up)).await.unwrap();
+    assert_eq!(cell, [false, true, false, false, false]);
 
-    // Acquire seeds
-    for user in users.iter_mut() {
-        let seed = client.get_seed().await.unwrap();
-        user.assign_seed(seed);
-        user.gen_client_key();
-    }
+    let cell = game.play(1, Action::Move(Direction::Left)).await.unwrap();
+    assert_eq!(cell, [true, true, false, false, true]);
+
+    let cell = game.play(0, Action::PickupEgg).await.unwrap();
+    assert_eq!(cell, [true, true, false, false, false]);
 
-    println!("register users");
+    let cell = game.play(2, Action::GetCell).await.unwrap();
+    assert_eq!(cell, [false, false, true, false, false]);
 
-    // Register
-    for user in users.iter_mut() {
-        let reg = client.register(&user.name).await.unwrap();
-        user.set_id(reg.id);
+    let dashboard = game.client.get_dashboard().await.unwrap();
+    assert_eq!(*dashboard.phase(), ServerState::ReadyForActions);
+    assert_eq!(dashboard.round(), 5);
+    assert!(dashboard.users().iter().all(|user| !user.action_submitted));
+}
+
+#[test]
+fn malformed_bodies_are_rejected() {
+    let rt = Runtime::new().unwrap();
+    let client = rt.block_on(WebClient::new_test(rocket())).unwrap();
+
+    let ck = gen_client_key();
+    let action = UserAction::move_player(&ck, Direction::Up);
+    let submission = DecryptionShareSubmission::new(0, (0, vec![1, 2, 3]));
+    let valid_bodies = vec![
+        msgpack::to_compact_vec(&action).unwrap(),
+        msgpack::to_compact_vec(&UserAction::<EncryptedWord>::LayEgg).unwrap(),
+        msgpack::to_compact_vec(&submission).unwrap(),
+    ];
+    let truncated = (
+        prop::sample::select(valid_bodies),
+        any::<prop::sample::Index>(),
+    )
+        .prop_map(|(body, index)| body[..index.index(body.len())].to_vec());
+    let garbage = prop::collection::vec(any::<u8>(), 0..512);
+
+    proptest!(|(
+        route in prop::sample::select(MSGPACK_ROUTES.to_vec()),
+        body in prop_oneof![garbage, truncated],
+    )| {
+        let status = rt.block_on(client.post_raw(route, body));
+        prop_assert!(status.class().is_client_error(), "{} answered {}", route, status);
+    });
+}
+
+#[rocket::async_test]
+async fn msgpack_limit_takes_bodies_up_to_it() {
+    let done = msgpack::to_compact_vec(&UserAction::<EncryptedWord>::Done).unwrap();
+    for (limit, expect_too_large) in [(done.len(), false), (done.len() - 1, true)] {
+        let limits = Limits::new().limit("msgpack", limit.into());
+        let server = rocket().configure(rocket::Config::figment().merge(("limits", limits)));
+        let client = WebClient::new_test(server).await.unwrap();
+        let status = client.post_raw("/done/0", done.clone()).await;
+        assert_eq!(status == Status::PayloadTooLarge, expect_too_large);
     }
+}
 
-    for user in users.iter_mut() {
-        let dashboard = client.get_dashboard().await.unwrap();
-        user.set_total_users(dashboard.get_names().len());
+#[cfg(feature = "sim")]
+#[rocket::async_test]
+async fn game_ends_after_max_rounds() {
+    let config = GameConfig::builder().max_rounds(1).build().unwrap();
+    let client = WebClient::new_test(rocket_with(config.clone()))
+        .await
+        .unwrap();
+    assert_eq!(client.get_param().await.unwrap().config, config);
+
+    let mut game = Game::start_with(client, 4).await.unwrap();
+    game.setup(&[false; BOARD_SIZE], &[(0, 0), (1, 1), (2, 2), (3, 3)])
+        .await
+        .unwrap();
+    game.play(0, Action::LayEgg).await.unwrap();
+    assert!(game.client.get_dashboard().await.unwrap().is_game_over());
+    let error = game.client.lay_egg(0).await.unwrap_err();
+    assert!(matches!(error, crate::Error::Phase(_)));
+}
+
+#[test]
+fn game_config_is_checked_against_the_circuits() {
+    assert_eq!(
+        GameConfig::builder().build().unwrap(),
+        GameConfig::default()
+    );
+    let unsupported = [
+        GameConfig::builder().players(3),
+        GameConfig::builder().board_dim(8),
+        GameConfig::builder().max_rounds(0),
+    ];
+    for builder in unsupported {
+        let error = builder.build().unwrap_err();
+        assert!(matches!(
+            error,
+            crate::Error::Protocol(ProtocolError::UnsupportedConfig { .. })
+        ));
     }
+}
+
+#[test]
+fn local_view_moves_on_the_config_board() {
+    let last = BOARD_DIM as u8 - 1;
+    let mut view = GameStateLocalView::new(0, last, 0, &GameConfig::default());
+    view.move_player(Direction::Up);
+    view.move_player(Direction::Right);
+    assert_eq!(view.my_coord(), (last, 0));
+}
 
-    println!("generate and submit server key share");
+#[rocket::async_test]
+async fn formats_are_negotiated() {
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    let get = |accept| client.send(Method::Get, "/param", accept, RequestBody::Empty);
+    let (json, msgpack) = (MediaType::JSON, MediaType::MsgPack);
+    let json = get(&json).await.unwrap();
+    let msgpack = get(&msgpack).await.unwrap();
+    assert!(matches!(json.body, ResponseBody::Json(_)));
+    assert!(matches!(msgpack.body, ResponseBody::MsgPack(_)));
+    let (json, msgpack) = (json.decode::<Param>(), msgpack.decode::<Param>());
+    let (json, msgpack) = (json.unwrap(), msgpack.unwrap());
+    assert_eq!(json.seed, msgpack.seed);
+    assert_eq!(json.config, msgpack.config);
+
+    // Bodies are taken as json too. This one is fine, just early
+    let WebClient::Test(client) = client else {
+        unreachable!()
+    };
+    let post = |content_type, body| client.post("/done/0").header(content_type).body(body);
+    let response = post(ContentType::JSON, r#""Done""#).dispatch().await;
+    assert_eq!(response.status(), Status::Conflict);
+    let response = post(ContentType::JSON, "{").dispatch().await;
+    assert_eq!(response.status(), Status::BadRequest);
+    let response = post(ContentType::Plain, r#""Done""#).dispatch().await;
+    assert_eq!(response.status(), Status::UnsupportedMediaType);
+}
 
-    // Generate and submit server key share
-    for user in users.iter_mut() {
-        set_parameter_set(PARAMETER);
-        println!("{} Gen cipher", user.name);
+#[rocket::async_test]
+async fn errors_come_back_typed() {
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    let error = client.get_fhe_output().await.unwrap_err();
+    let expected = PhaseError::new(ServerState::CompletedFhe, ServerState::ReadyForJoining);
+    assert_eq!(error, expected.into());
+
+    // So do requests turned down before getting to a route
+    let body = RequestBody::MsgPack(vec![0xc1]);
+    let path = "/request_action/0";
+    let response = client
+        .send(Method::Post, path, &MediaType::JSON, body)
+        .await;
+    let rejected = ProtocolError::Rejected {
+        status: 422,
+        reason: "Unprocessable Entity".to_string(),
+    };
+    assert_eq!(response.unwrap().decode::<UserId>(), Err(rejected.into()));
+
+    // The ID of the request comes back, to find it in the logs
+    let WebClient::Test(client) = client else {
+        unreachable!()
+    };
+    let request_id = Header::new(REQUEST_ID_HEADER, "0123abcd");
+    let response = client
+        .get("/fhe_output")
+        .header(request_id)
+        .dispatch()
+        .await;
+    assert_eq!(
+        response.headers().get_one(REQUEST_ID_HEADER),
+        Some("0123abcd")
+    );
+    let body = response.into_json::<serde_json::Value>().await.unwrap();
+    assert_eq!(body["code"], "wrong_phase");
+    assert_eq!(body["request_id"], "0123abcd");
+}
 
-        time!(
-            || {
-                user.gen_server_key_share();
-            },
-            format!("{} Gen server key share", user.name)
+#[rocket::async_test]
+async fn openapi_lists_every_route() {
+    let rocket = rocket();
+    // The swagger UI routes are the only ones without a handler fn name
+    let routes = rocket
+        .routes()
+        .filter(|route| route.name.is_some())
+        .map(|route| {
+            let path = route.uri.path().replace('<', "{").replace('>', "}");
+            (path, route.method.as_str().to_lowercase())
+        })
+        .collect_vec();
+    let client = rocket::local::asynchronous::Client::tracked(rocket)
+        .await
+        .unwrap();
+    let doc = client.get("/openapi.json").dispatch().await;
+    let doc: serde_json::Value = doc.into_json().await.unwrap();
+
+    assert_eq!(routes.len(), doc["paths"].as_object().unwrap().len());
+    for (path, method) in routes {
+        let operation = &doc["paths"][&path][&method];
+        assert!(
+            operation.is_object(),
+            "{} {} isn't documented",
+            method,
+            path
         );
+    }
+    let content = &doc["paths"]["/dashboard"]["get"]["responses"]["200"]["content"];
+    assert!(content["application/msgpack"].is_object());
+}
 
-        let user_id = user.id.unwrap();
-        let sks = user.server_key.as_ref().unwrap();
-        if user_id == 0 {
-            let sks = msgpack::to_vec(sks).unwrap();
-            println!("sks size {}", sks.len());
-        }
+#[cfg(feature = "sim")]
+#[test]
+fn malformed_actions_are_rejected() {
+    let rt = Runtime::new().unwrap();
+    let mut game = rt
+        .block_on(async {
+            let game = Game::start(4).await?;
+            game.setup(&[false; BOARD_SIZE], &[(0, 0); 4]).await?;
+            Ok::<_, Error>(game)
+        })
+        .unwrap();
+
+    proptest!(|(user_id in 0..8usize, direction in prop::collection::vec(any::<bool>(), 0..32))| {
+        prop_assume!(user_id >= 4 || direction.len() != 8);
+        let body = msgpack::to_compact_vec(&UserAction::MovePlayer { direction }).unwrap();
+        let route = format!("/request_action/{user_id}");
+        let status = rt.block_on(game.client.post_raw(&route, body));
+        prop_assert!(status.class().is_client_error(), "{} answered {}", route, status);
+    });
+
+    // Actions of another phase are turned away before their words are unpacked
+    let coord = UserAction::SetStartingCoord {
+        starting_coord: vec![true; 3],
+    };
+    let body = msgpack::to_compact_vec(&coord).unwrap();
+    let status = rt.block_on(game.client.post_raw("/request_action/0", body));
+    assert_eq!(status, Status::Conflict);
+
+    // None of it got to the action queue, the next round plays as usual
+    let cell = rt.block_on(game.play(0, Action::GetCell)).unwrap();
+    assert_eq!(cell, [true, true, true, true, false]);
+}
 
-        println!("{} Submit server key", user.name);
-        client.submit_sks(user_id, &sks).await.unwrap();
-        // Drop here to save mem
-        user.server_key = None;
+#[cfg(feature = "sim")]
+#[rocket::async_test]
+async fn recorded_game_replays() {
+    async fn play(client: WebClient) -> Result<(Game, Vec<Vec<bool>>), Error> {
+        let mut game = Game::start_with(client, 4).await?;
+        game.setup(&[false; BOARD_SIZE], &[(0, 0), (1, 1), (2, 2), (3, 3)])
+            .await?;
+        let cells = vec![
+            game.play(0, Action::LayEgg).await?,
+            game.play(1, Action::Move(Direction::Up)).await?,
+        ];
+        Ok((game, cells))
     }
 
-    println!("user 0 calls init game");
-
-    // User 0 encrypt initial eggs
-    let initial_eggs = [false; BOARD_SIZE];
-    let ck = users[0].ck.as_ref().unwrap();
-    client.init_game(ck, 0, &initial_eggs).await.unwrap();
+    let fixture = std::env::temp_dir().join(format!("chickens-{}.jsonl", std::process::id()));
+    let client = WebClient::new_test(rocket()).await.unwrap();
+    let (_, recorded) = play(WebClient::record(client, &fixture).unwrap())
+        .await
+        .unwrap();
+
+    let (game, replayed) = play(WebClient::replay(&fixture).unwrap()).await.unwrap();
+    assert_eq!(replayed, recorded);
+    // The fixture ends with the session
+    assert!(game.client.get_dashboard().await.is_err());
+    std::fs::remove_file(&fixture).unwrap();
+}
 
-    println!("users call set starting coords");
+#[cfg(all(feature = "sim", feature = "grpc"))]
+#[rocket::async_test]
+async fn game_over_grpc() {
+    use crate::grpc::proto;
+    use futures::StreamExt;
+    use rocket::data::ToByteUnit;
+
+    let seed = [0; 32];
+    setup(&seed);
+    let ss = MutexServerStorage::new(tokio::sync::Mutex::new(ServerStorage::new(seed)));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("grpc://{}", listener.local_addr().unwrap());
+    tokio::spawn(crate::grpc::serve(ss, listener, Limits::MESSAGE_PACK));
+
+    let mut game = Game::start_with(WebClient::new(&url), 4).await.unwrap();
+    game.setup(&[false; BOARD_SIZE], &[(0, 0), (1, 1), (2, 2), (3, 3)])
+        .await
+        .unwrap();
+    let cell = game.play(0, Action::LayEgg).await.unwrap();
+    assert_eq!(cell, [true, false, false, false, true]);
+
+    // The run is watched until it completes
+    let client = &game.client;
+    let ck = &game.players[1].ck;
+    client.move_player(ck, 1, Direction::Up).await.unwrap();
+    client.trigger_fhe_run(1).await.unwrap();
+    let statuses = client.watch_fhe().await.unwrap().collect::<Vec<_>>().await;
+    let status = statuses.last().unwrap().as_ref().unwrap();
+    assert_eq!(status.status, ServerState::CompletedFhe);
+    assert_eq!(status.output_user, Some(1));
+
+    // Errors come back with the status the HTTP API gives them
+    let done = msgpack::to_compact_vec(&UserAction::<EncryptedWord>::Done).unwrap();
+    let status = client.post_raw("/done/7", done.clone()).await;
+    assert_eq!(status, Status::NotFound);
+    let unregistered = ProtocolError::UnregisteredUser { user_id: 7 };
+    assert_eq!(client.done(7).await, Err(unregistered.into()));
+    let status = client.post_raw("/request_action/0", vec![0xc1]).await;
+    assert_eq!(status, Status::BadRequest);
+    // and with the ID of the call
+    let endpoint = url.replacen("grpc://", "http://", 1);
+    let mut raw = proto::chickens_client::ChickensClient::connect(endpoint)
+        .await
+        .unwrap();
+    let mut request = tonic::Request::new(proto::ActionRequest {
+        user_id: 7,
+        action: done,
+    });
+    let id = "0123abcd".parse().unwrap();
+    request.metadata_mut().insert("x-request-id", id);
+    let status = raw.done(request).await.unwrap_err();
+    let body: serde_json::Value = serde_json::from_str(status.message()).unwrap();
+    assert_eq!(body["request_id"], "0123abcd");
+
+    // Streamed bodies are held to the msgpack limit, as over HTTP
+    let ss = MutexServerStorage::new(tokio::sync::Mutex::new(ServerStorage::new(seed)));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = WebClient::new(&format!("grpc://{}", listener.local_addr().unwrap()));
+    tokio::spawn(crate::grpc::serve(ss, listener, 16.bytes()));
+    let status = client.post_raw("/submit_sks", vec![0; 16]).await;
+    assert_eq!(status, Status::BadRequest);
+    let status = client.post_raw("/submit_sks", vec![0; 17]).await;
+    assert_eq!(status, Status::PayloadTooLarge);
+    // A body that fails to read fails the upload, instead of sending what was read
+    let unreadable = std::env::temp_dir();
+    let submitted = client.submit_sks_from_file(&unreadable).await;
+    assert!(matches!(submitted, Err(crate::Error::Storage(_))));
+}
 
-    // Assign starting coords
-    let users_coords = vec![(0u8, 0u8), (0u8, 0u8), (0u8, 0u8), (0u8, 0u8)];
-    for user in users.iter_mut() {
-        user.assign_starting_coords(&users_coords[user.id.unwrap()]);
+#[cfg(feature = "sim")]
+#[rocket::async_test]
+async fn game_client_plays_a_round() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let server = rocket().configure(rocket::Config::figment().merge(("port", port)));
+    tokio::spawn(server.launch());
+    let url = format!("http://127.0.0.1:{port}");
+    while WebClient::new(&url).get_seed().await.is_err() {
+        sleep(Duration::from_millis(100)).await
     }
 
-    for (i, user) in users.iter_mut().enumerate() {
-        let ck = user.ck.as_ref().unwrap();
-        client
-            .set_starting_coords(ck, i, &user.starting_coords.unwrap())
-            .await
-            .unwrap();
+    let mut games = vec![];
+    for i in 0..4 {
+        if i == 1 {
+            let mut restarted = GameClient::new(WebClient::new(&url), "User 0");
+            assert_eq!(restarted.register(Some(0)).await.unwrap(), 0);
+        }
+        let mut game = GameClient::new(WebClient::new(&url), &format!("User {i}"));
+        assert_eq!(game.register(None).await.unwrap(), i);
+        games.push(game);
+    }
+    // A registration is only resumed while registration is open
+    let mut restarted = GameClient::new(WebClient::new(&url), "User 0");
+    assert!(matches!(
+        restarted.register(Some(0)).await,
+        Err(crate::Error::Phase(_))
+    ));
+    for game in games.iter_mut() {
+        assert_eq!(game.refresh().await.unwrap(), Phase::RegistrationClosed);
+        game.submit_key_share(None).await.unwrap();
+    }
+    for (i, game) in games.iter_mut().enumerate() {
+        assert_eq!(game.refresh().await.unwrap(), Phase::ReadyForSetup);
+        game.setup_game(i as u8, i as u8).await.unwrap();
+    }
+    for game in games.iter_mut() {
+        assert_eq!(game.refresh().await.unwrap(), Phase::ReadyForAction);
     }
 
-    println!("round 1 start");
-    println!("each user submit an action");
-
-    let directions = vec![Direction::Up, Direction::Down];
-    for (i, user) in users.iter_mut().enumerate() {
-        let ck = user.ck.as_ref().unwrap();
-        if i == 0 || i == 1 {
-            client.move_player(ck, i, directions[i]).await.unwrap();
-        } else if i == 2 {
-            client.lay_egg(i).await.unwrap();
-        } else {
-            client.pickup_egg(i).await.unwrap();
+    // A restarted client picks the game up again with its session
+    let session = games[2].session().unwrap();
+    games[2] = GameClient::new(WebClient::new(&url), "User 2");
+    assert_eq!(
+        games[2].resume(session).await.unwrap(),
+        Phase::ReadyForAction
+    );
+    assert_eq!(games[2].user_id(), Some(2));
+    assert!(games[2].view().is_some());
+
+    // Steps out of their phase are refused
+    assert!(games[0].decrypt().await.is_err());
+    assert!(games[0].act(Action::LayEgg).await.unwrap());
+    assert!(games[0].act(Action::LayEgg).await.is_err());
+    // Player 1 was too late, they help decrypting the output of player 0
+    assert!(!games[1].act(Action::Move(Direction::Up)).await.unwrap());
+
+    for game in games.iter_mut() {
+        while game.refresh().await.unwrap() != Phase::CompletedFhe {
+            sleep(Duration::from_millis(100)).await
         }
+        game.decrypt().await.unwrap();
     }
+    assert_eq!(games[0].phase(), Phase::AwaitingShares);
+    // Player 1 already submitted their share, only the round is left to finish
+    let session = games[1].session().unwrap();
+    games[1] = GameClient::new(WebClient::new(&url), "User 1");
+    assert_eq!(games[1].resume(session).await.unwrap(), Phase::Decrypted);
+    assert_eq!(games[0].refresh().await.unwrap(), Phase::Decrypted);
+    assert_eq!(
+        games[0].output(),
+        Some(&[true, false, false, false, true][..])
+    );
+
+    for game in games.iter_mut() {
+        game.finish_round().await.unwrap();
+    }
+    for game in games.iter_mut() {
+        assert_eq!(game.refresh().await.unwrap(), Phase::ReadyForAction);
+        assert_eq!(game.round(), 1);
+    }
+}
 
-    client.done(0).await.unwrap();
-
-    println!("any user calls trigger the run");
+#[cfg(feature = "keyring")]
+#[test]
+fn sealed_sessions_only_open_under_their_passphrase() {
+    use crate::secrets::{open, seal};
+    let sealed = seal("passphrase", b"session").unwrap();
+    assert_eq!(open("passphrase", &sealed).unwrap(), b"session");
+    assert!(open("another passphrase", &sealed).is_err());
+    assert!(open("passphrase", &sealed[..8]).is_err());
+}
 
-    // Admin runs the FHE computation
-    client.trigger_fhe_run(0).await.unwrap();
-    while client.trigger_fhe_run(0).await.unwrap() != ServerState::CompletedFhe {
-        sleep(Duration::from_secs(1)).await
+/// Checks the compiled circuits against a plaintext model of the game rules.
+/// Real FHE is far too slow for this many runs, so it needs the `sim` feature.
+#[cfg(feature = "sim")]
+mod reference {
+    use crate::fhe::gen_client_key;
+    use crate::types::*;
+    use crate::*;
+    use itertools::Itertools;
+    use proptest::prelude::*;
+
+    const TOTAL_USERS: usize = 4;
+
+    /// Plaintext reference of the game rules
+    #[derive(Debug, Clone)]
+    struct Board {
+        // (row, column) of every player
+        coords: Vec<(u8, u8)>,
+        // Row major
+        eggs: [bool; BOARD_SIZE],
     }
 
-    println!("users get fhe output and decrypt shares");
+    impl Board {
+        fn egg(&mut self, user_id: UserId) -> &mut bool {
+            let (x, y) = self.coords[user_id];
+            &mut self.eggs[x as usize * BOARD_DIM + y as usize]
+        }
 
-    // Users get FHE output, generate decryption shares, and submit decryption shares
-    for user in users.iter_mut() {
-        let fhe_output = client.get_fhe_output().await.unwrap();
+        fn apply(&mut self, user_id: UserId, turn: Action) {
+            let dim = BOARD_DIM as u8;
+            let (x, y) = &mut self.coords[user_id];
+            match turn {
+                Action::Move(Direction::Up) => *x = (*x + dim - 1) % dim,
+                Action::Move(Direction::Down) => *x = (*x + 1) % dim,
+                Action::Move(Direction::Left) => *y = (*y + dim - 1) % dim,
+                Action::Move(Direction::Right) => *y = (*y + 1) % dim,
+                Action::LayEgg => *self.egg(user_id) = true,
+                Action::PickupEgg => *self.egg(user_id) = false,
+                Action::GetCell => {}
+            }
+        }
 
-        user.set_fhe_out(fhe_output);
-        // user.gen_decryption_shares();
+        /// Which players stand on the cell of `user_id`, then whether it has an egg
+        fn cell(&mut self, user_id: UserId) -> Vec<bool> {
+            let coord = self.coords[user_id];
+            let mut cell = self.coords.iter().map(|c| *c == coord).collect_vec();
+            cell.push(*self.egg(user_id));
+            cell
+        }
+    }
 
-        // client
-        //     .submit_decryption_share(user.id.expect("exist now"), &&user.get_my_shares())
-        //     .await
-        //     .unwrap();
+    /// Play `turns` with the compiled circuits, then decrypt the cell of every player
+    fn run_circuits(board: &Board, turns: &[(UserId, Action)]) -> Vec<Vec<bool>> {
+        let cks = (0..TOTAL_USERS).map(|_| gen_client_key()).collect_vec();
+        let word = |user_id: UserId, action: UserAction<EncryptedWord>| {
+            action.unpack(user_id).into_word().expect("carries a word")
+        };
+
+        let coords = cks
+            .iter()
+            .zip_eq(&board.coords)
+            .enumerate()
+            .map(|(user_id, (ck, coord))| {
+                Some(word(user_id, UserAction::set_starting_coord(ck, coord)))
+            })
+            .collect();
+        let eggs = word(0, UserAction::init_game(&cks[0], &board.eggs));
+        let uas = turns
+            .iter()
+            .map(|(user_id, turn)| {
+                let action = match *turn {
+                    Action::Move(direction) => UserAction::move_player(&cks[*user_id], direction),
+                    Action::LayEgg => UserAction::LayEgg,
+                    Action::PickupEgg => UserAction::PickupEgg,
+                    Action::GetCell => UserAction::GetCell,
+                };
+                (*user_id, action.unpack(*user_id))
+            })
+            .collect_vec();
+
+        let state = evaluate_circuit(GameStateEnc { coords, eggs }, &uas);
+        (0..TOTAL_USERS)
+            .map(|user_id| {
+                let output = CircuitOutput::new(get_user_cell(&state, user_id));
+                let dss = cks
+                    .iter()
+                    .map(|ck| output.gen_decryption_share(ck))
+                    .collect_vec();
+                output.decrypt(&cks[user_id], &dss)
+            })
+            .collect()
     }
 
-    // TODO: should not hard code this
-    let correct_ouput = [
-        [true, false, false, false, false],
-        [false, true, false, false, false],
-        [false, false, true, true, false],
-        [false, false, true, true, false],
-    ];
+    fn turn() -> impl Strategy<Value = Action> {
+        let directions = vec![
+            Direction::Up,
+            Direction::Down,
+            Direction::Left,
+            Direction::Right,
+        ];
+        prop_oneof![
+            prop::sample::select(directions).prop_map(Action::Move),
+            Just(Action::LayEgg),
+            Just(Action::PickupEgg),
+            Just(Action::GetCell),
+        ]
+    }
+
+    fn board() -> impl Strategy<Value = Board> {
+        let coord = (0..BOARD_DIM as u8, 0..BOARD_DIM as u8);
+        (
+            prop::collection::vec(coord, TOTAL_USERS),
+            prop::array::uniform16(any::<bool>()),
+        )
+            .prop_map(|(coords, eggs)| Board { coords, eggs })
+    }
 
-    // Each user decrypt thier own cell
-    println!("Users decrypt their own cell");
-    // for user in users {
-    //     let decrypted_outs = user.decrypt_everything();
-    //     println!("{} sees {:?}", user.name, decrypted_outs);
-    //     assert_eq!(decrypted_outs, correct_ouput);
-    // }
-    Ok(())
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn circuits_match_reference(
+            board in board(),
+            turns in prop::collection::vec((0..TOTAL_USERS, turn()), 0..8),
+        ) {
+            let cells = run_circuits(&board, &turns);
+
+            let mut expected = board;
+            for (user_id, turn) in turns.iter() {
+                expected.apply(*user_id, *turn);
+            }
+            for (user_id, cell) in cells.into_iter().enumerate() {
+                prop_assert_eq!(cell, expected.cell(user_id), "cell of user {}", user_id);
+            }
+        }
+    }
 }
 
-#[rocket::async_test]
-async fn full_flow() {
-    // Need to fix the global variable thing to allow multiple flow run
-    // run_flow_with_n_users(2).await.unwrap();
-    // run_flow_with_n_users(3).await.unwrap();
-    run_flow_with_n_users(4).await.unwrap();
+#[test]
+fn tampered_decryption_share_is_rejected() {
+    let mut submission = DecryptionShareSubmission::new(0, (0, vec![1, 2, 3]));
+    assert!(submission.verify().is_ok());
+
+    submission.decryption_share.1[1] = 42;
+    assert!(matches!(
+        submission.verify(),
+        Err(crate::Error::Crypto(CryptoError::DigestMismatch {
+            user_id: 0,
+            ..
+        }))
+    ));
+}
+
+#[test]
+fn coords_to_binary_puts_y_in_the_high_half() {
+    let bits = coords_to_binary::<16>(1, 2);
+    let expected = (0..16).map(|i| i == 0 || i == 9).collect_vec();
+    assert_eq!(bits.to_vec(), expected);
 }
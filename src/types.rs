@@ -1,34 +1,29 @@
 use crate::circuit::PARAMETER;
-use crate::dashboard::{Dashboard, RegisteredUser};
+use crate::config::{EdgeBehavior, GameConfig};
+use crate::dashboard::{Dashboard, FheStatus, RegisteredUser};
+use crate::error::{CryptoError, Error, PhaseError, ProtocolError, StorageError};
+use crate::fhe::{self, set_parameter_set, Encryptor, FheBool, MultiPartyDecryptor};
 use itertools::Itertools;
-use phantom_zone::{
-    evaluator::NonInteractiveMultiPartyCrs,
-    keys::CommonReferenceSeededNonInteractiveMultiPartyServerKeyShare, parameters::BoolParameters,
-    set_parameter_set, Encryptor, FheBool, KeySwitchWithId, MultiPartyDecryptor,
-    NonInteractiveSeededFheBools, SampleExtractor,
-};
+use rayon::prelude::*;
 use rocket::serde::{Deserialize, Serialize};
-use rocket::tokio::sync::Mutex;
-use rocket::Responder;
+use rocket::tokio::sync::{watch, Mutex};
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::fmt::Display;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::Arc;
 use tabled::Table;
-use thiserror::Error;
+use utoipa::ToSchema;
 
-pub type ClientKey = phantom_zone::ClientKey;
+pub type ClientKey = fhe::ClientKey;
 pub type UserId = usize;
 
 pub(crate) type Seed = [u8; 32];
-pub(crate) type ServerKeyShare = CommonReferenceSeededNonInteractiveMultiPartyServerKeyShare<
-    Vec<Vec<u64>>,
-    BoolParameters<u64>,
-    NonInteractiveMultiPartyCrs<Seed>,
->;
+pub type ServerKeyShare = fhe::ServerKeyShare;
 
 pub type Word = Vec<FheBool>;
-pub(crate) type EncryptedWord = NonInteractiveSeededFheBools<Vec<u64>, Seed>;
+pub(crate) type EncryptedWord = fhe::EncryptedWord;
 
 /// Decryption share for a word from one user.
 pub type DecryptionShare = Vec<u64>;
@@ -36,10 +31,28 @@ pub type DecryptionShare = Vec<u64>;
 /// Decryption share with output id
 pub(crate) type AnnotatedDecryptionShare = (usize, DecryptionShare);
 
+/// blake3 digest of a submitted payload
+pub(crate) type Digest = [u8; 32];
+
+/// Hash the compact msgpack encoding of `payload`, the same encoding used on the wire.
+/// The encoding is streamed into the hasher so the payload isn't buffered twice.
+pub(crate) fn payload_digest<T: Serialize>(payload: &T) -> Digest {
+    let mut hasher = blake3::Hasher::new();
+    rmp_serde::encode::write(&mut hasher, payload).expect("payload is serializable");
+    *hasher.finalize().as_bytes()
+}
+
+/// Side of the board the circuits are compiled for
 pub const BOARD_DIM: usize = 4;
 pub const BOARD_SIZE: usize = BOARD_DIM * BOARD_DIM;
-
-#[derive(Copy, Clone)]
+/// Players the circuits are compiled for
+pub const TOTAL_USERS: usize = 4;
+/// Bits of an encoded coordinate, x then y
+const COORD_BITS: usize = 16;
+/// Bits of an encoded direction
+const DIRECTION_BITS: usize = 8;
+
+#[derive(Copy, Clone, Debug)]
 #[repr(u8)]
 pub enum Direction {
     Up = 0,
@@ -59,7 +72,7 @@ fn u8_to_binary<const N: usize>(v: u8) -> [bool; N] {
     result
 }
 
-fn coords_to_binary<const N: usize>(x: u8, y: u8) -> [bool; N] {
+pub(crate) fn coords_to_binary<const N: usize>(x: u8, y: u8) -> [bool; N] {
     let mut result = [false; N];
     for i in 0..N / 2 {
         if (x >> i) & 1 == 1 {
@@ -67,39 +80,50 @@ fn coords_to_binary<const N: usize>(x: u8, y: u8) -> [bool; N] {
         }
     }
     for i in N / 2..N {
-        if (y >> i) & 1 == 1 {
+        if (y >> (i - N / 2)) & 1 == 1 {
             result[i] = true;
         }
     }
     result
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
 pub struct GameStateLocalView {
     user_id: UserId,
     my_coord: (u8, u8),
     eggs_laid: Vec<Vec<bool>>,
+    config: GameConfig,
 }
 
 impl GameStateLocalView {
     // x is the row index, y is the column index
-    pub fn new(x: u8, y: u8, user_id: UserId) -> Self {
+    pub fn new(x: u8, y: u8, user_id: UserId, config: &GameConfig) -> Self {
+        let dim = config.board_dim();
         Self {
             user_id,
             my_coord: (x, y),
-            eggs_laid: vec![vec![false; BOARD_DIM]; BOARD_DIM],
+            eggs_laid: vec![vec![false; dim]; dim],
+            config: config.clone(),
         }
     }
 
     pub fn move_player(&mut self, dir: Direction) {
+        let dim = self.config.board_dim() as u8;
         let (x, y) = &mut self.my_coord;
-        match dir {
-            Direction::Up => *x = (*x - 1 + BOARD_DIM as u8) % BOARD_DIM as u8,
-            Direction::Down => *x = (*x + 1) % BOARD_DIM as u8,
-            Direction::Left => *y = (*y - 1 + BOARD_DIM as u8) % BOARD_DIM as u8,
-            Direction::Right => *y = (*y + 1) % BOARD_DIM as u8,
+        match self.config.edge() {
+            EdgeBehavior::Wrap => match dir {
+                Direction::Up => *x = (*x + dim - 1) % dim,
+                Direction::Down => *x = (*x + 1) % dim,
+                Direction::Left => *y = (*y + dim - 1) % dim,
+                Direction::Right => *y = (*y + 1) % dim,
+            },
         }
     }
+    pub fn my_coord(&self) -> (u8, u8) {
+        self.my_coord
+    }
+
     pub fn get_egg(&mut self) -> &mut bool {
         let (x, y) = self.my_coord;
         &mut self.eggs_laid[x as usize][y as usize]
@@ -119,17 +143,18 @@ impl GameStateLocalView {
         println!("----------------Local View-------------------");
         println!("My coordinates {:?}", self.my_coord);
 
+        let dim = self.config.board_dim();
         let mut data = vec![];
-        for _ in 0..BOARD_DIM {
-            let cells = (0..BOARD_DIM).map(|_| "_".to_string()).collect_vec();
+        for _ in 0..dim {
+            let cells = (0..dim).map(|_| "_".to_string()).collect_vec();
             data.push(cells)
         }
 
         let (my_x, my_y) = self.my_coord;
         data[my_x as usize][my_y as usize] = format!("(🐓{})", self.user_id).to_string();
 
-        for x in 0..BOARD_DIM {
-            for y in 0..BOARD_DIM {
+        for x in 0..dim {
+            for y in 0..dim {
                 if self.eggs_laid[x][y] {
                     data[x][y] = [data[x][y].to_string(), "🥚".to_string()]
                         .join("")
@@ -145,9 +170,10 @@ impl GameStateLocalView {
         println!("(Only my cell is decrypted)");
         println!("My coordinates {:?}", self.my_coord);
 
+        let dim = self.config.board_dim();
         let mut data = vec![];
-        for _ in 0..BOARD_DIM {
-            let cells = (0..BOARD_DIM).map(|_| "🌫️".to_string()).collect_vec();
+        for _ in 0..dim {
+            let cells = (0..dim).map(|_| "🌫️".to_string()).collect_vec();
             data.push(cells)
         }
 
@@ -155,12 +181,13 @@ impl GameStateLocalView {
         let (x, y) = (my_x as usize, my_y as usize);
         data[x][y] = "".to_string();
 
-        for user in 0..4 {
+        let players = self.config.players();
+        for user in 0..players {
             if output[user] {
                 data[x][y] = [data[x][y].to_string(), format!("(🐓{})", user).to_string()].concat()
             }
         }
-        if output[4] {
+        if output[players] {
             data[x][y] = [data[x][y].to_string(), "🥚".to_string()].concat()
         }
 
@@ -175,7 +202,7 @@ pub struct GameStateEnc {
 }
 
 /// Encrypted input words contributed from one user
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 #[serde(crate = "rocket::serde")]
 pub enum UserAction<T> {
     InitGame { initial_eggs: T },
@@ -202,6 +229,20 @@ impl<T> Display for UserAction<T> {
     }
 }
 
+impl<T> UserAction<T> {
+    /// The word carried by the action, if any
+    pub fn into_word(self) -> Option<T> {
+        match self {
+            UserAction::InitGame { initial_eggs } => Some(initial_eggs),
+            UserAction::SetStartingCoord { starting_coord } => Some(starting_coord),
+            UserAction::MovePlayer { direction } => Some(direction),
+            UserAction::LayEgg | UserAction::PickupEgg | UserAction::GetCell | UserAction::Done => {
+                None
+            }
+        }
+    }
+}
+
 impl UserAction<EncryptedWord> {
     pub fn init_game(ck: &ClientKey, initial_eggs: &[bool]) -> Self {
         let initial_eggs = ck.encrypt(initial_eggs);
@@ -210,13 +251,13 @@ impl UserAction<EncryptedWord> {
 
     pub fn set_starting_coord(ck: &ClientKey, coords: &(u8, u8)) -> Self {
         let (x, y) = coords;
-        let starting_coord = ck.encrypt(coords_to_binary::<16>(*x, *y).as_slice());
+        let starting_coord = ck.encrypt(coords_to_binary::<COORD_BITS>(*x, *y).as_slice());
 
         Self::SetStartingCoord { starting_coord }
     }
 
     pub fn move_player(ck: &ClientKey, direction: Direction) -> Self {
-        let direction = u8_to_binary::<8>(direction as u8);
+        let direction = u8_to_binary::<DIRECTION_BITS>(direction as u8);
         Self::MovePlayer {
             direction: ck.encrypt(direction.as_slice()),
         }
@@ -226,13 +267,13 @@ impl UserAction<EncryptedWord> {
         set_parameter_set(PARAMETER);
         match &self {
             UserAction::InitGame { initial_eggs } => UserAction::InitGame {
-                initial_eggs: unpack_word(initial_eggs, user_id),
+                initial_eggs: fhe::unpack_word(initial_eggs, user_id),
             },
             UserAction::SetStartingCoord { starting_coord } => UserAction::SetStartingCoord {
-                starting_coord: unpack_word(starting_coord, user_id),
+                starting_coord: fhe::unpack_word(starting_coord, user_id),
             },
             UserAction::MovePlayer { direction } => UserAction::MovePlayer {
-                direction: unpack_word(direction, user_id),
+                direction: fhe::unpack_word(direction, user_id),
             },
             UserAction::LayEgg => UserAction::LayEgg,
             UserAction::PickupEgg => UserAction::PickupEgg,
@@ -240,30 +281,75 @@ impl UserAction<EncryptedWord> {
             UserAction::Done => UserAction::Done,
         }
     }
+
+    /// [`Self::unpack`] for actions off the wire, a word that doesn't unpack or doesn't fit
+    /// the circuits is an error instead of a panic
+    pub(crate) fn unpack_checked(
+        &self,
+        user_id: UserId,
+        config: &GameConfig,
+    ) -> Result<UserAction<Word>, Error> {
+        let action =
+            panic::catch_unwind(AssertUnwindSafe(|| self.unpack(user_id))).map_err(|_| {
+                ProtocolError::UndecodablePayload {
+                    payload: self.to_string(),
+                }
+            })?;
+        action.validate(config)?;
+        Ok(action)
+    }
 }
 
-fn unpack_word(word: &EncryptedWord, user_id: UserId) -> Word {
-    word.unseed::<Vec<Vec<u64>>>()
-        .key_switch(user_id)
-        .extract_all()
+impl UserAction<Word> {
+    /// Reject words the circuits can't take, before they get to an FHE run
+    pub(crate) fn validate(&self, config: &GameConfig) -> Result<(), Error> {
+        let (expect, got) = match self {
+            UserAction::InitGame { initial_eggs } => (config.board_size(), initial_eggs.len()),
+            UserAction::SetStartingCoord { starting_coord } => (COORD_BITS, starting_coord.len()),
+            UserAction::MovePlayer { direction } => (DIRECTION_BITS, direction.len()),
+            UserAction::LayEgg | UserAction::PickupEgg | UserAction::GetCell | UserAction::Done => {
+                return Ok(())
+            }
+        };
+        if expect == got {
+            Ok(())
+        } else {
+            Err(ProtocolError::MalformedPayload {
+                payload: self.to_string(),
+                expect,
+                got,
+            }
+            .into())
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct CircuitOutput {
+    #[schema(value_type = Vec<crate::openapi::Ciphertext>)]
     cell: Word,
 }
 
 impl CircuitOutput {
-    pub(crate) fn new(cell: Word) -> Self {
+    pub fn new(cell: Word) -> Self {
         Self { cell }
     }
 
+    pub(crate) fn n_bits(&self) -> usize {
+        self.cell.len()
+    }
+
+    /// Bits are shared in parallel on the current rayon pool, [`crate::with_thread_pool`]
+    /// sizes it
     pub fn gen_decryption_share(&self, ck: &ClientKey) -> DecryptionShare {
         let dec_share = self
             .cell
-            .iter()
-            .map(|out_bit| ck.gen_decryption_share(out_bit))
-            .collect_vec();
+            .par_iter()
+            .map(|out_bit| {
+                set_parameter_set(PARAMETER);
+                ck.gen_decryption_share(out_bit)
+            })
+            .collect();
         dec_share
     }
 
@@ -283,48 +369,7 @@ impl CircuitOutput {
     }
 }
 
-#[derive(Debug, Error)]
-pub(crate) enum Error {
-    #[error("Wrong server state: expect {expect} but got {got}")]
-    WrongServerState { expect: String, got: String },
-    #[error("User #{user_id} is unregistered")]
-    UnregisteredUser { user_id: usize },
-    #[error("The ciphertext from user #{user_id} not found")]
-    CipherNotFound { user_id: UserId },
-    #[error("Decryption share from user {user_id} not found")]
-    DecryptionShareNotFound { user_id: UserId },
-    /// Temporary here
-    #[error("Output not ready")]
-    OutputNotReady,
-    #[error("Init action not performed yet")]
-    GameNotInitedYet,
-    #[error("Cells not found")]
-    CellNotFound,
-}
-
-#[derive(Responder)]
-pub(crate) enum ErrorResponse {
-    #[response(status = 500, content_type = "json")]
-    ServerError(String),
-    #[response(status = 404, content_type = "json")]
-    NotFoundError(String),
-}
-
-impl From<Error> for ErrorResponse {
-    fn from(error: Error) -> Self {
-        match error {
-            Error::WrongServerState { .. }
-            | Error::CipherNotFound { .. }
-            | Error::GameNotInitedYet
-            | Error::CellNotFound => ErrorResponse::ServerError(error.to_string()),
-            Error::DecryptionShareNotFound { .. }
-            | Error::UnregisteredUser { .. }
-            | Error::OutputNotReady => ErrorResponse::NotFoundError(error.to_string()),
-        }
-    }
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
 pub enum ServerState {
     /// Users are allowed to join the computation
     ReadyForJoining,
@@ -336,6 +381,8 @@ pub enum ServerState {
     ReadyForRunning,
     RunningFhe,
     CompletedFhe,
+    /// The last round of the game is done
+    GameOver,
 }
 
 impl ServerState {
@@ -343,10 +390,7 @@ impl ServerState {
         if *self == expect {
             Ok(self)
         } else {
-            Err(Error::WrongServerState {
-                expect: expect.to_string(),
-                got: self.to_string(),
-            })
+            Err(PhaseError::new(expect, self).into())
         }
     }
 
@@ -366,6 +410,7 @@ pub(crate) type MutexServerStorage = Arc<Mutex<ServerStorage>>;
 #[derive(Debug)]
 pub(crate) struct ServerStorage {
     pub(crate) seed: Seed,
+    pub(crate) config: GameConfig,
     pub(crate) state: ServerState,
     pub(crate) users: Vec<UserRecord>,
 
@@ -375,12 +420,17 @@ pub(crate) struct ServerStorage {
     pub(crate) circuit_output: Option<CircuitOutput>,
     pub(crate) round: usize,
     pub(crate) decryption_shares: DecryptionSharesMap,
+    /// Whose cell the ongoing or last FHE run outputs
+    pub(crate) output_user: Option<UserId>,
+    /// Signalled on every state transition
+    pub(crate) changes: watch::Sender<()>,
 }
 
 impl ServerStorage {
     pub(crate) fn new(seed: Seed) -> Self {
         Self {
             seed,
+            config: GameConfig::default(),
             state: ServerState::ReadyForJoining,
             users: vec![],
 
@@ -389,6 +439,8 @@ impl ServerStorage {
             circuit_output: None,
             round: 0,
             decryption_shares: HashMap::new(),
+            output_user: None,
+            changes: watch::channel(()).0,
         }
     }
 
@@ -399,6 +451,7 @@ impl ServerStorage {
             name: name.to_string(),
             storage: UserStorage::Empty,
             ready_for_new_round: false,
+            action_submitted: false,
         });
         RegisteredUser::new(user_id, name)
     }
@@ -410,13 +463,14 @@ impl ServerStorage {
 
     pub(crate) fn transit(&mut self, state: ServerState) {
         self.state.transit(state.clone());
-        println!("Sever state {}", state);
+        self.changes.send_replace(());
+        tracing::info!("Server state {}", state);
     }
 
     pub(crate) fn get_user(&mut self, user_id: UserId) -> Result<&mut UserRecord, Error> {
         self.users
             .get_mut(user_id)
-            .ok_or(Error::UnregisteredUser { user_id })
+            .ok_or(ProtocolError::UnregisteredUser { user_id }.into())
     }
 
     pub(crate) fn check_cipher_submission(&self) -> bool {
@@ -441,17 +495,25 @@ impl ServerStorage {
             if let Some(sks) = user.storage.get_cipher_sks() {
                 server_key_shares.push(sks.clone());
             } else {
-                return Err(Error::CipherNotFound { user_id });
+                return Err(StorageError::CipherNotFound { user_id }.into());
             }
         }
         Ok(server_key_shares)
     }
 
+    pub(crate) fn get_fhe_status(&self) -> FheStatus {
+        FheStatus {
+            status: self.state.clone(),
+            output_user: self.output_user,
+        }
+    }
+
     pub(crate) fn get_dashboard(&self) -> Dashboard {
         Dashboard::new(
             &self.state,
             &self.users.iter().map_into().collect_vec(),
             self.round,
+            &self.config,
         )
     }
 }
@@ -462,6 +524,8 @@ pub(crate) struct UserRecord {
     pub(crate) name: String,
     pub(crate) storage: UserStorage,
     pub(crate) ready_for_new_round: bool,
+    /// Requested an action this round
+    pub(crate) action_submitted: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -488,19 +552,87 @@ impl UserStorage {
     }
 }
 
+/// What a client sets up before playing
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(crate = "rocket::serde")]
+pub struct Param {
+    /// Common reference seed, 32 bytes
+    #[schema(value_type = Vec<u8>)]
+    pub seed: Seed,
+    pub config: GameConfig,
+}
+
 /// ([`Word`] index, user_id) -> decryption share
 pub type DecryptionSharesMap = HashMap<(usize, UserId), DecryptionShare>;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, ToSchema)]
 #[serde(crate = "rocket::serde")]
-pub(crate) struct SksSubmission {
+pub(crate) struct SksSubmission<'a> {
     pub(crate) user_id: UserId,
-    pub(crate) sks: ServerKeyShare,
+    /// Borrowed when submitting, owned once received. Saves cloning the largest payload
+    #[schema(value_type = Object)]
+    pub(crate) sks: Cow<'a, ServerKeyShare>,
+    /// blake3 of the key share's compact msgpack encoding
+    #[schema(value_type = Vec<u8>)]
+    pub(crate) digest: Digest,
+}
+
+impl<'a> SksSubmission<'a> {
+    pub(crate) fn new(user_id: UserId, sks: &'a ServerKeyShare) -> Self {
+        let digest = payload_digest(sks);
+        Self {
+            user_id,
+            sks: Cow::Borrowed(sks),
+            digest,
+        }
+    }
+
+    /// Check the received key share against the digest computed by the client
+    pub(crate) fn verify(&self) -> Result<(), Error> {
+        if payload_digest(&self.sks) == self.digest {
+            Ok(())
+        } else {
+            Err(CryptoError::DigestMismatch {
+                payload: "server key share".to_string(),
+                user_id: self.user_id,
+            }
+            .into())
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, ToSchema)]
 #[serde(crate = "rocket::serde")]
 pub(crate) struct DecryptionShareSubmission {
     pub(crate) user_id: UserId,
+    /// Output id, then the share
+    #[schema(value_type = Vec<Object>)]
     pub(crate) decryption_share: AnnotatedDecryptionShare,
+    /// blake3 of the share's compact msgpack encoding
+    #[schema(value_type = Vec<u8>)]
+    pub(crate) digest: Digest,
+}
+
+impl DecryptionShareSubmission {
+    pub(crate) fn new(user_id: UserId, decryption_share: AnnotatedDecryptionShare) -> Self {
+        let digest = payload_digest(&decryption_share);
+        Self {
+            user_id,
+            decryption_share,
+            digest,
+        }
+    }
+
+    /// Check the received decryption share against the digest computed by the client
+    pub(crate) fn verify(&self) -> Result<(), Error> {
+        if payload_digest(&self.decryption_share) == self.digest {
+            Ok(())
+        } else {
+            Err(CryptoError::DigestMismatch {
+                payload: "decryption share".to_string(),
+                user_id: self.user_id,
+            }
+            .into())
+        }
+    }
 }